@@ -1,3 +1,21 @@
+export interface StopTimes {
+  current_time: string,
+  stops: Stop[],
+  trips: TimetableUpdate[],
+  next_departure: BaseStopTime | null
+}
+export interface Stop {
+  stop_id: string,
+  stop_code?: string,
+  stop_name: string,
+  stop_lat: number,
+  stop_lon: number,
+  platform_code?: string
+}
+export interface ErrorBody {
+  code: string,
+  message: string
+}
 export interface TimetableUpdate {
   base: BaseStopTime,
   realtime: RealtimeUpdate
@@ -0,0 +1,29 @@
+-- This file should undo anything in `up.sql`
+
+ALTER TABLE stop_time
+  DROP COLUMN continuous_pickup,
+  DROP COLUMN continuous_drop_off,
+  DROP COLUMN timepoint,
+  ALTER COLUMN shape_dist_traveled TYPE integer;
+
+ALTER TABLE stop
+  DROP COLUMN stop_url,
+  DROP COLUMN stop_timezone,
+  DROP COLUMN wheelchair_boarding,
+  DROP COLUMN level_id,
+  DROP COLUMN platform_code;
+
+ALTER TABLE trip
+  DROP COLUMN wheelchair_accessible,
+  DROP COLUMN bikes_allowed;
+
+ALTER TABLE route
+  DROP COLUMN route_desc,
+  DROP COLUMN route_url,
+  DROP COLUMN route_sort_order,
+  DROP COLUMN continuous_pickup,
+  DROP COLUMN continuous_drop_off;
+
+ALTER TABLE agency
+  DROP COLUMN agency_fare_url,
+  DROP COLUMN agency_email;
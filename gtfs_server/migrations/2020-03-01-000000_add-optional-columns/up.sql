@@ -0,0 +1,30 @@
+-- Optional columns from the GTFS spec, so feeds using them can be copied in.
+
+ALTER TABLE agency
+  ADD COLUMN agency_fare_url text NULL,
+  ADD COLUMN agency_email text NULL;
+
+ALTER TABLE route
+  ADD COLUMN route_desc text NULL,
+  ADD COLUMN route_url text NULL,
+  ADD COLUMN route_sort_order integer NULL,
+  ADD COLUMN continuous_pickup integer NULL,
+  ADD COLUMN continuous_drop_off integer NULL;
+
+ALTER TABLE trip
+  ADD COLUMN wheelchair_accessible integer NULL,
+  ADD COLUMN bikes_allowed integer NULL;
+
+ALTER TABLE stop
+  ADD COLUMN stop_url text NULL,
+  ADD COLUMN stop_timezone text NULL,
+  ADD COLUMN wheelchair_boarding integer NULL,
+  ADD COLUMN level_id text NULL,
+  ADD COLUMN platform_code text NULL;
+
+ALTER TABLE stop_time
+  ADD COLUMN continuous_pickup integer NULL,
+  ADD COLUMN continuous_drop_off integer NULL,
+  ADD COLUMN timepoint integer NULL,
+  -- the spec allows fractional distances
+  ALTER COLUMN shape_dist_traveled TYPE double precision;
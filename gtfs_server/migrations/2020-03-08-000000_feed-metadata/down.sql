@@ -0,0 +1,8 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX feed_source_idx;
+
+ALTER TABLE feed
+  DROP COLUMN source,
+  DROP COLUMN etag,
+  DROP COLUMN last_modified,
+  DROP COLUMN imported_at;
@@ -0,0 +1,8 @@
+-- Where each feed was imported from, so unchanged feeds are not downloaded again.
+ALTER TABLE feed
+  ADD COLUMN source text NULL,
+  ADD COLUMN etag text NULL,
+  ADD COLUMN last_modified text NULL,
+  ADD COLUMN imported_at timestamptz NOT NULL DEFAULT now();
+
+CREATE INDEX feed_source_idx ON feed (source);
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX feed_content_hash_idx;
+
+ALTER TABLE feed DROP COLUMN content_hash;
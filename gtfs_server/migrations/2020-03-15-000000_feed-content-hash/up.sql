@@ -0,0 +1,4 @@
+-- Hash of the feed's files, so that an identical feed is not imported twice.
+ALTER TABLE feed ADD COLUMN content_hash text NULL;
+
+CREATE INDEX feed_content_hash_idx ON feed (content_hash);
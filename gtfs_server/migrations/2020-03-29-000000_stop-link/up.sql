@@ -0,0 +1,13 @@
+-- Stops in different feeds which are at the same place, e.g. an interchange
+-- served by two operators. Each link is stored in both directions.
+CREATE TABLE stop_link(
+  feed_id integer NOT NULL,
+  stop_id text NOT NULL,
+  linked_feed_id integer NOT NULL,
+  linked_stop_id text NOT NULL,
+  -- from the override file, rather than found by distance and name
+  manual boolean NOT NULL DEFAULT false,
+  CONSTRAINT stop_link_pk PRIMARY KEY (feed_id, stop_id, linked_feed_id, linked_stop_id),
+  CONSTRAINT stop_link_feed_fk FOREIGN KEY (feed_id) references feed(feed_id) ON DELETE CASCADE,
+  CONSTRAINT stop_link_linked_feed_fk FOREIGN KEY (linked_feed_id) references feed(feed_id) ON DELETE CASCADE
+);
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX stop_time_stop_departure_idx;
+create index index_stop_time_stop_id on stop_time(stop_id);
+DROP TABLE service_date;
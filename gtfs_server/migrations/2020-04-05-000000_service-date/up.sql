@@ -0,0 +1,44 @@
+-- The days on which each service runs, from calendar and calendar_date, so
+-- that stop times can be found by joining on the service date.
+-- Filled in by the importer for each feed.
+CREATE TABLE service_date(
+  feed_id integer NOT NULL,
+  service_id text NOT NULL,
+  date date NOT NULL,
+  CONSTRAINT service_date_pk PRIMARY KEY (feed_id, service_id, date),
+  CONSTRAINT service_date_feed_fk FOREIGN KEY (feed_id) references feed(feed_id)
+) PARTITION BY LIST (feed_id);
+
+-- partitions for the feeds already imported
+DO $$
+DECLARE
+  f integer;
+BEGIN
+  FOR f IN SELECT feed_id FROM feed LOOP
+    EXECUTE format('CREATE TABLE %I PARTITION OF service_date FOR VALUES IN (%s)', 'service_date_' || f, f);
+  END LOOP;
+END $$;
+
+INSERT INTO service_date (feed_id, service_id, date)
+SELECT c.feed_id, c.service_id, d::date FROM calendar c
+  CROSS JOIN generate_series(c.start_date, c.end_date, '1 day'::interval) d
+  WHERE CASE extract(isodow FROM d)
+      WHEN 1 THEN c.monday
+      WHEN 2 THEN c.tuesday
+      WHEN 3 THEN c.wednesday
+      WHEN 4 THEN c.thursday
+      WHEN 5 THEN c.friday
+      WHEN 6 THEN c.saturday
+      ELSE c.sunday
+    END
+  AND NOT EXISTS (
+    SELECT 1 FROM calendar_date cd
+    WHERE cd.feed_id = c.feed_id AND cd.service_id = c.service_id
+      AND cd.date = d::date AND cd.exception_type = 2
+  )
+UNION
+SELECT feed_id, service_id, date FROM calendar_date WHERE exception_type = 1;
+
+-- departures from a stop in a range of times are an index range scan
+DROP INDEX index_stop_time_stop_id;
+CREATE INDEX stop_time_stop_departure_idx ON stop_time (feed_id, stop_id, departure_time);
@@ -1,17 +1,44 @@
 use derive_more::{Display, From};
 use futures::future::FutureExt;
-use log::{debug, error, info};
+use log::{debug, error, info, warn};
 use prost::Message;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::sync::broadcast;
 
+use crate::config::RealtimeConfig;
 use crate::gtfs_data::RealtimeUpdateManager;
+use crate::metrics::Metrics;
 use crate::protobuf::gtfs_realtime::FeedMessage;
+use crate::vehicles::Vehicles;
 
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct UrlConfig {
+    pub url: String,
+    #[serde(default)]
+    pub header: HashMap<String, String>,
+}
+
+/// Either a list of `[[feed]]`s, or a single feed's `url` and `header`.
 #[derive(serde::Deserialize)]
-struct UrlConfig {
-    url: String,
-    header: HashMap<String, String>,
+#[serde(untagged)]
+enum FeedsFile {
+    Feeds { feed: Vec<UrlConfig> },
+    Feed(UrlConfig),
+}
+
+impl FeedsFile {
+    fn feeds(self) -> Vec<UrlConfig> {
+        match self {
+            FeedsFile::Feeds { feed } => feed,
+            FeedsFile::Feed(feed) => vec![feed],
+        }
+    }
+}
+
+pub fn parse_feeds_file(s: &str) -> Result<Vec<UrlConfig>, toml::de::Error> {
+    Ok(toml::from_str::<FeedsFile>(s)?.feeds())
 }
 
 #[derive(From, Display)]
@@ -23,25 +50,89 @@ enum RealtimeApiError {
     ParseUrlConfig(toml::de::Error),
 }
 
-async fn get_realtime_feed_config(path: &str) -> Result<UrlConfig, RealtimeApiError> {
+async fn get_realtime_feed_config(path: &str) -> Result<Vec<UrlConfig>, RealtimeApiError> {
     let s = tokio::fs::read_to_string(path).await?;
 
-    let config = toml::from_str(&s)?;
+    Ok(parse_feeds_file(&s)?)
+}
 
-    Ok(config)
+/// Merges feeds into one, renaming entities whose ids are used in more than
+/// one feed. Its timestamp is the oldest of theirs.
+fn merge_feeds<'a>(feeds: impl IntoIterator<Item = &'a FeedMessage>) -> FeedMessage {
+    let mut merged = FeedMessage::default();
+    merged.header.gtfs_realtime_version = "2.0".into();
+    let mut ids = HashSet::new();
+    for (i, feed) in feeds.into_iter().enumerate() {
+        merged.header.timestamp = match (merged.header.timestamp, feed.header.timestamp) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+        for entity in &feed.entity {
+            let mut entity = entity.clone();
+            if !ids.insert(entity.id.clone()) {
+                entity.id = format!("{}:{}", i, entity.id);
+                ids.insert(entity.id.clone());
+            }
+            merged.entity.push(entity);
+        }
+    }
+    merged
 }
 
-pub async fn fetch_data(realtime_manager: Arc<Mutex<RealtimeUpdateManager>>) {
-    let client = reqwest::Client::new();
-    let mut interval = tokio::time::interval(std::time::Duration::from_secs(30));
+/// Drops the feeds which are no longer configured, and those last fetched
+/// more than `max_age` before `now`, so that a feed which keeps failing
+/// isn't merged in forever.
+fn retain_current(
+    latest: &mut HashMap<String, (FeedMessage, Instant)>,
+    config: &[UrlConfig],
+    now: Instant,
+    max_age: Duration,
+) {
+    latest.retain(|url, (_, fetched_at)| {
+        if !config.iter().any(|f| &f.url == url) {
+            return false;
+        }
+        let stale = now.saturating_duration_since(*fetched_at) > max_age;
+        if stale {
+            warn!(
+                "Dropping the realtime data from {}, last fetched more than {}s ago",
+                url,
+                max_age.as_secs()
+            );
+        }
+        !stale
+    });
+}
 
-    let realtime_config_filepath = std::env::var("REALTIME_CONFIG_FILEPATH")
-        .expect("REALTIME_CONFIG_FILEPATH must be defined");
+/// Fetches realtime data from each configured feed every
+/// `fetch_interval_secs`, and loads them merged, sending to `updates` after
+/// each time. A feed which couldn't be fetched is included as it was last
+/// fetched, for up to `max_age_secs`.
+pub async fn fetch_data(
+    realtime_config: RealtimeConfig,
+    realtime_manager: Arc<Mutex<RealtimeUpdateManager>>,
+    vehicles: Arc<Mutex<Vehicles>>,
+    updates: broadcast::Sender<()>,
+    metrics: Metrics,
+) {
+    let client = reqwest::Client::builder()
+        .timeout(realtime_config.request_timeout())
+        .build()
+        .expect("the http client should be created");
+    let mut interval = tokio::time::interval(realtime_config.fetch_interval());
 
     interval.tick().await; // 0 second tick
 
+    // the latest feed from each url, and when it was fetched
+    let mut latest: HashMap<String, (FeedMessage, Instant)> = HashMap::new();
+    let max_age = realtime_config.max_age();
+
     loop {
-        let config = match get_realtime_feed_config(&realtime_config_filepath).await {
+        let config = match &realtime_config.feeds_file {
+            Some(path) => get_realtime_feed_config(path).await,
+            None => Ok(realtime_config.feed.clone()),
+        };
+        let config = match config {
             Ok(c) => c,
             Err(e) => {
                 error!("Error fetching gtfs realtime configs: {}", e);
@@ -53,16 +144,53 @@ pub async fn fetch_data(realtime_manager: Arc<Mutex<RealtimeUpdateManager>>) {
         let timer_future = interval.tick().fuse();
         let c = client.clone();
         let rm = realtime_manager.clone();
+        let u = updates.clone();
+        let v = vehicles.clone();
+        let m = metrics.clone();
+        let latest = &mut latest;
         let request_future = async move {
-            match send_request(&c, &config).await {
-                Ok(feed) => {
-                    debug!("Fetched {} entities from {}", feed.entity.len(), config.url);
-                    (*rm.lock().unwrap()).load_feed(feed);
+            let responses = futures::future::join_all(config.iter().map(|feed| {
+                let c = &c;
+                async move {
+                    let start = std::time::Instant::now();
+                    (send_request(c, feed).await, start.elapsed())
                 }
-                Err(e) => {
-                    error!("Error fetching gtfs data: {}", e);
+            }))
+            .await;
+            let mut fetched = false;
+            for (i, (feed_config, (response, duration))) in config.iter().zip(responses).enumerate()
+            {
+                match response {
+                    Ok(feed) => {
+                        debug!(
+                            "Fetched {} entities from {}",
+                            feed.entity.len(),
+                            feed_config.url
+                        );
+                        m.fetch_succeeded(i, duration, &feed);
+                        latest.insert(feed_config.url.clone(), (feed, Instant::now()));
+                        fetched = true;
+                    }
+                    Err(e) => {
+                        error!("Error fetching gtfs data from {}: {}", feed_config.url, e);
+                        m.fetch_failed(i, duration);
+                    }
                 }
             }
+            m.retain_feeds(config.len());
+            if !fetched {
+                return;
+            }
+            retain_current(latest, &config, Instant::now(), max_age);
+            let feed = merge_feeds(
+                config
+                    .iter()
+                    .filter_map(|f| latest.get(&f.url).map(|(feed, _)| feed)),
+            );
+            (*v.lock().unwrap()).load_feed(&feed);
+            (*rm.lock().unwrap()).load_feed(feed);
+            // there may be no one listening
+            let _ = u.send(());
         }
         .fuse();
 
@@ -95,3 +223,94 @@ async fn send_request(
     let message = FeedMessage::decode(bytes)?;
     Ok(message)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protobuf::gtfs_realtime::{FeedEntity, FeedHeader};
+
+    fn feed(timestamp: Option<u64>, ids: &[&str]) -> FeedMessage {
+        FeedMessage {
+            header: FeedHeader {
+                gtfs_realtime_version: "2.0".into(),
+                incrementality: None,
+                timestamp,
+            },
+            entity: ids
+                .iter()
+                .map(|&id| FeedEntity {
+                    id: id.into(),
+                    ..Default::default()
+                })
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn merge() {
+        let merged = merge_feeds(&[
+            feed(Some(20), &["a", "b"]),
+            feed(None, &[]),
+            feed(Some(10), &["b", "c"]),
+        ]);
+        assert_eq!(merged.header.timestamp, Some(10));
+        assert_eq!(
+            merged.entity.iter().map(|e| &e.id[..]).collect::<Vec<_>>(),
+            vec!["a", "b", "2:b", "c"]
+        );
+    }
+
+    #[test]
+    fn stale() {
+        let url = |u: &str| UrlConfig {
+            url: u.into(),
+            header: HashMap::new(),
+        };
+        let config = vec![url("https://a"), url("https://b")];
+        let fetched_at = Instant::now();
+        let mut latest = HashMap::new();
+        latest.insert(
+            "https://a".to_string(),
+            (feed(Some(10), &["a"]), fetched_at),
+        );
+        latest.insert(
+            "https://b".to_string(),
+            (feed(Some(20), &["b"]), fetched_at + Duration::from_secs(60)),
+        );
+        latest.insert("https://c".to_string(), (feed(None, &[]), fetched_at));
+        let max_age = Duration::from_secs(300);
+
+        retain_current(&mut latest, &config, fetched_at + max_age, max_age);
+        let mut urls = latest.keys().map(|u| &u[..]).collect::<Vec<_>>();
+        urls.sort_unstable();
+        assert_eq!(urls, vec!["https://a", "https://b"]);
+
+        // a hasn't been fetched for too long
+        retain_current(
+            &mut latest,
+            &config,
+            fetched_at + Duration::from_secs(301),
+            max_age,
+        );
+        assert_eq!(latest.keys().collect::<Vec<_>>(), vec!["https://b"]);
+        let merged = merge_feeds(
+            config
+                .iter()
+                .filter_map(|f| latest.get(&f.url).map(|(f, _)| f)),
+        );
+        assert_eq!(merged.header.timestamp, Some(20));
+    }
+
+    #[test]
+    fn config() {
+        let feeds = |s| parse_feeds_file(s).unwrap();
+        let single = feeds("url = \"https://a\"\n[header]\nKey = \"1\"\n");
+        assert_eq!(single.len(), 1);
+        assert_eq!(single[0].header["Key"], "1");
+        let list = feeds("[[feed]]\nurl = \"https://a\"\n[[feed]]\nurl = \"https://b\"\n");
+        assert_eq!(
+            list.iter().map(|f| &f.url[..]).collect::<Vec<_>>(),
+            vec!["https://a", "https://b"]
+        );
+    }
+}
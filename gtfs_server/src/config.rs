@@ -0,0 +1,384 @@
+//! The server's settings, read from the toml file at `SERVER_CONFIG_FILEPATH`
+//! (if it's set), then overridden by environment variables.
+
+use derive_more::Display;
+use serde::Deserialize;
+use std::fmt::Display;
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::api_fetcher::{self, UrlConfig};
+
+#[derive(Debug, Display)]
+pub enum ConfigError {
+    #[display(fmt = "Could not read {}: {}", _0, _1)]
+    Read(String, std::io::Error),
+    #[display(fmt = "Invalid config file {}: {}", _0, _1)]
+    Parse(String, toml::de::Error),
+    #[display(fmt = "Invalid {}={:?}: {}", _0, _1, _2)]
+    Env(&'static str, String, String),
+    #[display(fmt = "{}", _0)]
+    Invalid(String),
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// LISTEN_ADDRESS
+    pub listen: SocketAddr,
+    /// RUST_LOG, filters in env_logger's format like `info` or
+    /// `transit_server=debug`
+    pub log: Option<String>,
+    pub database: DatabaseConfig,
+    pub stop_times: StopTimesConfig,
+    pub realtime: RealtimeConfig,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct DatabaseConfig {
+    /// DATABASE_URL
+    pub url: Option<String>,
+    /// DATABASE_POOL_SIZE
+    pub pool_size: u32,
+    /// DATABASE_CONNECTION_TIMEOUT_SECS, how long a request waits for a free
+    /// connection
+    pub connection_timeout_secs: u64,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum StopTimesSource {
+    /// a query for each request
+    Database,
+    /// an in-memory copy of the timetable, see `timetable.rs`
+    Memory,
+}
+
+impl FromStr for StopTimesSource {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "database" => Ok(StopTimesSource::Database),
+            "memory" => Ok(StopTimesSource::Memory),
+            _ => Err("should be memory or database".to_string()),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(default, deny_unknown_fields)]
+pub struct StopTimesConfig {
+    /// STOP_TIMES_SOURCE
+    pub source: StopTimesSource,
+    /// STOP_TIMES_RANGE_START_MINS, how long ago the departures start if the
+    /// request doesn't say
+    pub range_start_mins: u32,
+    /// STOP_TIMES_RANGE_END_MINS, how far ahead the departures end if the
+    /// request doesn't say
+    pub range_end_mins: u32,
+    /// STOP_TIMES_DELAY_SLACK_MINS, how much earlier than the range
+    /// scheduled departures are looked for, in case they are delayed into it
+    pub delay_slack_mins: u32,
+    /// STOP_TIMES_NEXT_DEPARTURE_WITHIN_DAYS, how far after the range to look
+    /// for the next departure when there are none in it
+    pub next_departure_within_days: u32,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct RealtimeConfig {
+    /// REALTIME_CONFIG_FILEPATH, a toml file with the feeds, read again
+    /// before each fetch. Either this or `feed` should be given.
+    pub feeds_file: Option<String>,
+    pub feed: Vec<UrlConfig>,
+    /// REALTIME_FETCH_INTERVAL_SECS
+    pub fetch_interval_secs: u64,
+    /// REALTIME_REQUEST_TIMEOUT_SECS
+    pub request_timeout_secs: u64,
+    /// REALTIME_MAX_AGE_SECS, how old the realtime data can be before the
+    /// server isn't ready, and how long a feed which can't be fetched is
+    /// still merged in for
+    pub max_age_secs: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            listen: ([127, 0, 0, 1], 6789).into(),
+            log: None,
+            database: DatabaseConfig::default(),
+            stop_times: StopTimesConfig::default(),
+            realtime: RealtimeConfig::default(),
+        }
+    }
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        DatabaseConfig {
+            url: None,
+            pool_size: 10,
+            connection_timeout_secs: 30,
+        }
+    }
+}
+
+impl Default for StopTimesConfig {
+    fn default() -> Self {
+        StopTimesConfig {
+            source: StopTimesSource::Database,
+            range_start_mins: 2,
+            range_end_mins: 720,
+            delay_slack_mins: 30,
+            next_departure_within_days: 7,
+        }
+    }
+}
+
+impl Default for RealtimeConfig {
+    fn default() -> Self {
+        RealtimeConfig {
+            feeds_file: None,
+            feed: vec![],
+            fetch_interval_secs: 30,
+            request_timeout_secs: 20,
+            max_age_secs: 300,
+        }
+    }
+}
+
+impl Config {
+    /// Reads the config from `SERVER_CONFIG_FILEPATH` and the environment,
+    /// checking that it's valid.
+    pub fn load() -> Result<Self, ConfigError> {
+        let mut config = match std::env::var("SERVER_CONFIG_FILEPATH") {
+            Ok(path) => {
+                let s = std::fs::read_to_string(&path)
+                    .map_err(|e| ConfigError::Read(path.clone(), e))?;
+                toml::from_str(&s).map_err(|e| ConfigError::Parse(path, e))?
+            }
+            Err(_) => Config::default(),
+        };
+        config.override_from_env(|name| std::env::var(name).ok())?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn override_from_env(
+        &mut self,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<(), ConfigError> {
+        let env = &env;
+        set(env, "LISTEN_ADDRESS", &mut self.listen)?;
+        set_option(env, "RUST_LOG", &mut self.log)?;
+
+        let d = &mut self.database;
+        set_option(env, "DATABASE_URL", &mut d.url)?;
+        set(env, "DATABASE_POOL_SIZE", &mut d.pool_size)?;
+        set(
+            env,
+            "DATABASE_CONNECTION_TIMEOUT_SECS",
+            &mut d.connection_timeout_secs,
+        )?;
+
+        let s = &mut self.stop_times;
+        set(env, "STOP_TIMES_SOURCE", &mut s.source)?;
+        set(env, "STOP_TIMES_RANGE_START_MINS", &mut s.range_start_mins)?;
+        set(env, "STOP_TIMES_RANGE_END_MINS", &mut s.range_end_mins)?;
+        set(env, "STOP_TIMES_DELAY_SLACK_MINS", &mut s.delay_slack_mins)?;
+        set(
+            env,
+            "STOP_TIMES_NEXT_DEPARTURE_WITHIN_DAYS",
+            &mut s.next_departure_within_days,
+        )?;
+
+        let r = &mut self.realtime;
+        set_option(env, "REALTIME_CONFIG_FILEPATH", &mut r.feeds_file)?;
+        set(
+            env,
+            "REALTIME_FETCH_INTERVAL_SECS",
+            &mut r.fetch_interval_secs,
+        )?;
+        set(
+            env,
+            "REALTIME_REQUEST_TIMEOUT_SECS",
+            &mut r.request_timeout_secs,
+        )?;
+        set(env, "REALTIME_MAX_AGE_SECS", &mut r.max_age_secs)?;
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        let invalid = |s: &str| Err(ConfigError::Invalid(s.to_string()));
+        if self.database.url.is_none() {
+            return invalid("database.url or DATABASE_URL must be set");
+        }
+        if self.database.pool_size == 0 {
+            return invalid("database.pool_size must be at least 1");
+        }
+        if self.database.connection_timeout_secs == 0 {
+            return invalid("database.connection_timeout_secs must be at least 1");
+        }
+        if self.realtime.fetch_interval_secs == 0 {
+            return invalid("realtime.fetch_interval_secs must be at least 1");
+        }
+        if self.realtime.request_timeout_secs == 0 {
+            return invalid("realtime.request_timeout_secs must be at least 1");
+        }
+        if self.realtime.max_age_secs == 0 {
+            return invalid("realtime.max_age_secs must be at least 1");
+        }
+        match (&self.realtime.feeds_file, self.realtime.feed.is_empty()) {
+            (Some(_), false) => invalid(
+                "realtime.feeds_file (or REALTIME_CONFIG_FILEPATH) and [[realtime.feed]] can't both be set",
+            ),
+            (None, true) => invalid(
+                "realtime.feeds_file, REALTIME_CONFIG_FILEPATH or [[realtime.feed]] must be set",
+            ),
+            // it's read again before each fetch, but should be valid now
+            (Some(path), true) => {
+                let s = std::fs::read_to_string(path)
+                    .map_err(|e| ConfigError::Read(path.clone(), e))?;
+                api_fetcher::parse_feeds_file(&s)
+                    .map_err(|e| ConfigError::Parse(path.clone(), e))?;
+                Ok(())
+            }
+            (None, false) => Ok(()),
+        }
+    }
+}
+
+impl DatabaseConfig {
+    pub fn connection_timeout(&self) -> Duration {
+        Duration::from_secs(self.connection_timeout_secs)
+    }
+}
+
+impl RealtimeConfig {
+    pub fn fetch_interval(&self) -> Duration {
+        Duration::from_secs(self.fetch_interval_secs)
+    }
+
+    pub fn request_timeout(&self) -> Duration {
+        Duration::from_secs(self.request_timeout_secs)
+    }
+
+    pub fn max_age(&self) -> Duration {
+        Duration::from_secs(self.max_age_secs)
+    }
+}
+
+fn set<T>(
+    env: impl Fn(&str) -> Option<String>,
+    name: &'static str,
+    field: &mut T,
+) -> Result<(), ConfigError>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    if let Some(value) = env(name) {
+        *field = value
+            .parse()
+            .map_err(|e: T::Err| ConfigError::Env(name, value, e.to_string()))?;
+    }
+    Ok(())
+}
+
+fn set_option<T>(
+    env: impl Fn(&str) -> Option<String>,
+    name: &'static str,
+    field: &mut Option<T>,
+) -> Result<(), ConfigError>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    if let Some(value) = env(name) {
+        let parsed = value
+            .parse()
+            .map_err(|e: T::Err| ConfigError::Env(name, value, e.to_string()))?;
+        *field = Some(parsed);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn config(toml: &str, env: &[(&str, &str)]) -> Result<Config, ConfigError> {
+        let mut config: Config =
+            toml::from_str(toml).map_err(|e| ConfigError::Parse("".into(), e))?;
+        let env: HashMap<_, _> = env.iter().cloned().collect();
+        config.override_from_env(|name| env.get(name).map(|v| v.to_string()))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    #[test]
+    fn file_and_env() {
+        let c = config(
+            r#"
+listen = "0.0.0.0:8080"
+
+[database]
+url = "postgres://file"
+pool_size = 4
+
+[stop_times]
+source = "memory"
+range_end_mins = 60
+
+[[realtime.feed]]
+url = "https://example.com/realtime.pb"
+"#,
+            &[
+                ("DATABASE_URL", "postgres://env"),
+                ("STOP_TIMES_RANGE_START_MINS", "5"),
+            ],
+        )
+        .unwrap();
+        assert_eq!(c.listen, ([0, 0, 0, 0], 8080).into());
+        assert_eq!(c.database.url.as_deref(), Some("postgres://env"));
+        assert_eq!(c.database.pool_size, 4);
+        assert_eq!(c.database.connection_timeout_secs, 30);
+        assert_eq!(c.stop_times.source, StopTimesSource::Memory);
+        assert_eq!(
+            (c.stop_times.range_start_mins, c.stop_times.range_end_mins),
+            (5, 60)
+        );
+        assert_eq!(c.realtime.feed.len(), 1);
+        assert_eq!(c.realtime.fetch_interval_secs, 30);
+    }
+
+    #[test]
+    fn errors() {
+        let feed = "[[realtime.feed]]\nurl = \"https://a\"\n";
+        let error = |toml: &str, env: &[(&str, &str)]| config(toml, env).unwrap_err().to_string();
+
+        assert_eq!(error(feed, &[]), "database.url or DATABASE_URL must be set");
+        let url = [("DATABASE_URL", "postgres://env")];
+        assert!(config(feed, &url).is_ok());
+        assert_eq!(
+            error(feed, &[url[0], ("STOP_TIMES_SOURCE", "disk")]),
+            "Invalid STOP_TIMES_SOURCE=\"disk\": should be memory or database"
+        );
+        assert_eq!(
+            error(feed, &[url[0], ("DATABASE_POOL_SIZE", "0")]),
+            "database.pool_size must be at least 1"
+        );
+        assert!(error(feed, &[url[0], ("LISTEN_ADDRESS", "localhost")])
+            .starts_with("Invalid LISTEN_ADDRESS=\"localhost\""));
+        assert!(error("", &url).contains("must be set"));
+        assert!(error(&format!("port = 1\n{}", feed), &url).contains("unknown field `port`"));
+        assert!(
+            error(feed, &[url[0], ("REALTIME_CONFIG_FILEPATH", "a.toml")])
+                .contains("can't both be set")
+        );
+    }
+}
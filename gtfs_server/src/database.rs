@@ -1,11 +1,14 @@
 use diesel::pg::PgConnection;
 use diesel::r2d2::{ConnectionManager, Pool};
-use std::env;
 
-pub fn create_connection_pool() -> ConnectionPool {
-    let url = &env::var("DATABASE_URL").expect("DATABASE_URL must be defined");
+use crate::config::DatabaseConfig;
+
+pub fn create_connection_pool(config: &DatabaseConfig) -> ConnectionPool {
+    let url = config.url.as_deref().expect("the database url must be set");
     let manager = ConnectionManager::<DbConnection>::new(url);
     Pool::builder()
+        .max_size(config.pool_size)
+        .connection_timeout(config.connection_timeout())
         .build(manager)
         .unwrap_or_else(|_| panic!("Could not create pool for database: {}", &url))
 }
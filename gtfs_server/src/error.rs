@@ -0,0 +1,203 @@
+use derive_more::{Display, From};
+use diesel::result::DatabaseErrorKind;
+use serde::Serialize;
+use std::convert::Infallible;
+use warp::http::StatusCode;
+use warp::reject::{InvalidHeader, InvalidQuery, MethodNotAllowed, MissingHeader};
+use warp::{Rejection, Reply};
+
+#[derive(Debug, From, Display)]
+pub enum ServerError {
+    #[display(fmt = "Database error: {}", _0)]
+    DbError(diesel::result::Error),
+    #[display(fmt = "Could not get a database connection: {}", _0)]
+    PoolError(diesel::r2d2::PoolError),
+    #[display(fmt = "Tokio error: {}", _0)]
+    TokioError(tokio::task::JoinError),
+    #[display(fmt = "No stop has the code {}", _0)]
+    #[from(ignore)]
+    UnknownStop(String),
+    #[display(fmt = "Unknown format {}, should be pb or json", _0)]
+    #[from(ignore)]
+    UnknownFormat(String),
+    #[display(fmt = "No realtime data has been fetched yet")]
+    NoRealtimeData,
+}
+impl warp::reject::Reject for ServerError {}
+
+impl From<ServerError> for Rejection {
+    fn from(e: ServerError) -> Rejection {
+        warp::reject::custom(e)
+    }
+}
+
+/// The body of every error response, e.g.
+/// `{"code": "unknown_stop", "message": "No stop has the code 1234"}`.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct ErrorBody {
+    pub code: &'static str,
+    pub message: String,
+}
+
+impl ServerError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ServerError::UnknownStop(_) => StatusCode::NOT_FOUND,
+            ServerError::UnknownFormat(_) => StatusCode::BAD_REQUEST,
+            ServerError::PoolError(_) | ServerError::NoRealtimeData => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            ServerError::DbError(diesel::result::Error::DatabaseError(
+                DatabaseErrorKind::UnableToSendCommand,
+                _,
+            )) => StatusCode::SERVICE_UNAVAILABLE,
+            ServerError::DbError(_) | ServerError::TokioError(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        }
+    }
+
+    pub fn body(&self) -> ErrorBody {
+        // details of database and internal errors are only logged
+        let (code, message) = match self {
+            ServerError::UnknownStop(_) => ("unknown_stop", self.to_string()),
+            ServerError::UnknownFormat(_) => ("unknown_format", self.to_string()),
+            ServerError::NoRealtimeData => ("no_realtime_data", self.to_string()),
+            _ if self.status() == StatusCode::SERVICE_UNAVAILABLE => (
+                "database_unavailable",
+                "The database is unavailable".to_string(),
+            ),
+            _ => ("internal_error", "Internal server error".to_string()),
+        };
+        ErrorBody { code, message }
+    }
+}
+
+/// Turns rejections into a JSON error body with a matching status code.
+pub async fn handle_rejection(err: Rejection) -> Result<impl Reply, Infallible> {
+    let (status, body) = if let Some(e) = err.find::<ServerError>() {
+        if e.status().is_server_error() {
+            log::error!("{}", e);
+        }
+        (e.status(), e.body())
+    } else if err.is_not_found() {
+        (
+            StatusCode::NOT_FOUND,
+            ErrorBody {
+                code: "not_found",
+                message: "Not found".to_string(),
+            },
+        )
+    } else if let Some(e) = err.find::<InvalidQuery>() {
+        (
+            StatusCode::BAD_REQUEST,
+            ErrorBody {
+                code: "invalid_query",
+                message: e.to_string(),
+            },
+        )
+    } else if let Some(e) = err.find::<MissingHeader>() {
+        (
+            StatusCode::BAD_REQUEST,
+            ErrorBody {
+                code: "missing_header",
+                message: e.to_string(),
+            },
+        )
+    } else if let Some(e) = err.find::<InvalidHeader>() {
+        (
+            StatusCode::BAD_REQUEST,
+            ErrorBody {
+                code: "invalid_header",
+                message: e.to_string(),
+            },
+        )
+    } else if let Some(e) = err.find::<MethodNotAllowed>() {
+        (
+            StatusCode::METHOD_NOT_ALLOWED,
+            ErrorBody {
+                code: "method_not_allowed",
+                message: e.to_string(),
+            },
+        )
+    } else {
+        log::error!("Unhandled rejection: {:?}", err);
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            ErrorBody {
+                code: "internal_error",
+                message: "Internal server error".to_string(),
+            },
+        )
+    };
+    Ok(warp::reply::with_status(warp::reply::json(&body), status))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use warp::Filter;
+
+    #[test]
+    fn status_and_body() {
+        let e = ServerError::UnknownStop("1234".to_string());
+        assert_eq!(e.status(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            e.body(),
+            ErrorBody {
+                code: "unknown_stop",
+                message: "No stop has the code 1234".to_string(),
+            }
+        );
+
+        let e = ServerError::DbError(diesel::result::Error::DatabaseError(
+            DatabaseErrorKind::UnableToSendCommand,
+            Box::new("server closed the connection".to_string()),
+        ));
+        assert_eq!(e.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            e.body(),
+            ErrorBody {
+                code: "database_unavailable",
+                message: "The database is unavailable".to_string(),
+            }
+        );
+
+        let e = ServerError::DbError(diesel::result::Error::NotFound);
+        assert_eq!(e.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(
+            e.body(),
+            ErrorBody {
+                code: "internal_error",
+                message: "Internal server error".to_string(),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn rejections() {
+        let filter = warp::path!("stop" / String)
+            .and(warp::query::<std::collections::HashMap<String, u32>>())
+            .and_then(|code: String, _| async move {
+                Err::<String, _>(warp::reject::custom(ServerError::UnknownStop(code)))
+            })
+            .recover(handle_rejection);
+
+        let r = warp::test::request().path("/stop/1").reply(&filter).await;
+        assert_eq!(r.status(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            r.body(),
+            r#"{"code":"unknown_stop","message":"No stop has the code 1"}"#
+        );
+
+        let r = warp::test::request()
+            .path("/stop/1?a=b")
+            .reply(&filter)
+            .await;
+        assert_eq!(r.status(), StatusCode::BAD_REQUEST);
+
+        let r = warp::test::request().path("/nothing").reply(&filter).await;
+        assert_eq!(r.status(), StatusCode::NOT_FOUND);
+        assert_eq!(r.body(), r#"{"code":"not_found","message":"Not found"}"#);
+    }
+}
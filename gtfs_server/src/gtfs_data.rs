@@ -1,5 +1,7 @@
-use crate::protobuf::gtfs_realtime::{FeedMessage, TripUpdate, VehicleDescriptor};
-use chrono::NaiveDate;
+use crate::protobuf::gtfs_realtime::{
+    feed_header, FeedHeader, FeedMessage, TripUpdate, VehicleDescriptor,
+};
+use chrono::{DateTime, NaiveDate, TimeZone, Utc};
 use log::warn;
 // used because Equivalent trait is more flexible than Borrow trait.
 use indexmap::{Equivalent, IndexMap};
@@ -18,6 +20,16 @@ impl Equivalent<TripUpdateKey> for TripUpdateKeyRef<'_> {
 
 pub struct RealtimeUpdateManager {
     trip_updates: IndexMap<TripUpdateKey, TripUpdate>,
+    /// the latest feed, and when it was loaded
+    feed: Option<(FeedMessage, DateTime<Utc>)>,
+}
+
+/// The kinds of entity in a realtime feed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EntityKind {
+    TripUpdate,
+    VehiclePosition,
+    Alert,
 }
 
 #[allow(dead_code)]
@@ -25,12 +37,13 @@ impl RealtimeUpdateManager {
     pub fn new() -> Self {
         Self {
             trip_updates: IndexMap::new(),
+            feed: None,
         }
     }
     pub fn load_feed(&mut self, feed: FeedMessage) {
         self.trip_updates.clear();
-        for entity in feed.entity {
-            if let Some(trip_update) = entity.trip_update {
+        for entity in &feed.entity {
+            if let Some(trip_update) = entity.trip_update.clone() {
                 let trip_id = match trip_update.trip.trip_id.clone() {
                     Some(id) => id,
                     None => {
@@ -57,6 +70,66 @@ impl RealtimeUpdateManager {
                     .insert(TripUpdateKey(start_date, trip_id.clone()), trip_update);
             }
         }
+        self.feed = Some((feed, Utc::now()));
+    }
+    /// When the latest feed was created upstream, or when it was loaded if
+    /// it doesn't say, or None if there hasn't been one.
+    pub fn timestamp(&self) -> Option<DateTime<Utc>> {
+        let (feed, loaded_at) = self.feed.as_ref()?;
+        let upstream = feed
+            .header
+            .timestamp
+            .and_then(|t| Utc.timestamp_opt(t as i64, 0).single());
+        Some(upstream.unwrap_or(*loaded_at))
+    }
+    /// The entities of one kind in the latest feed, as a feed, or None if
+    /// there hasn't been one. Its timestamp is the upstream feed's, or when it
+    /// was loaded if it has none.
+    pub fn feed_message(&self, kind: EntityKind) -> Option<FeedMessage> {
+        let (feed, _) = self.feed.as_ref()?;
+        let entity = feed
+            .entity
+            .iter()
+            .filter_map(|e| {
+                let mut e = e.clone();
+                // an entity may have more than one kind
+                match kind {
+                    EntityKind::TripUpdate => {
+                        e.trip_update.as_ref()?;
+                        e.vehicle = None;
+                        e.alert = None;
+                    }
+                    EntityKind::VehiclePosition => {
+                        e.vehicle.as_ref()?;
+                        e.trip_update = None;
+                        e.alert = None;
+                    }
+                    EntityKind::Alert => {
+                        e.alert.as_ref()?;
+                        e.trip_update = None;
+                        e.vehicle = None;
+                    }
+                }
+                Some(e)
+            })
+            .collect();
+        Some(FeedMessage {
+            header: FeedHeader {
+                gtfs_realtime_version: "2.0".into(),
+                incrementality: Some(feed_header::Incrementality::FullDataset as i32),
+                timestamp: self.timestamp().map(|t| t.timestamp() as u64),
+            },
+            entity,
+        })
+    }
+    /// The number of entities of one kind in the latest feed.
+    pub fn entity_count(&self, kind: EntityKind) -> usize {
+        let entities = self.feed.iter().flat_map(|(feed, _)| &feed.entity);
+        match kind {
+            EntityKind::TripUpdate => entities.filter(|e| e.trip_update.is_some()).count(),
+            EntityKind::VehiclePosition => entities.filter(|e| e.vehicle.is_some()).count(),
+            EntityKind::Alert => entities.filter(|e| e.alert.is_some()).count(),
+        }
     }
     pub fn get_realtime_updates<'a, I: IntoIterator<Item = RealtimeQueryKey<'a>>>(
         &self,
@@ -83,7 +156,6 @@ impl RealtimeUpdateManager {
                         //dbg!(&trip_update.trip, &trip_update.stop_time_update, &stop_time_update_opt, &key.stop_sequence);
 
                         if let Some(stop_time_update) = stop_time_update_opt {
-
                             if let Some(stop_event) = &stop_time_update.departure {
                                 if let Some(delay) = stop_event.delay {
                                     realtime_data.delay = Some(delay);
@@ -270,4 +342,28 @@ mod tests {
             })]
         );
     }
+
+    #[test]
+    fn feed_message_by_kind() {
+        let mut manager = RealtimeUpdateManager::new();
+        assert_eq!(manager.feed_message(EntityKind::TripUpdate), None);
+
+        let mut both = tu("trip2", "20200101", vec![], None);
+        both.id = "j".into();
+        both.vehicle = Some(VehiclePosition::default());
+        manager.load_feed(FeedMessage {
+            header: h(),
+            entity: vec![tu("trip1", "20200101", vec![], None), both],
+        });
+
+        let trip_updates = manager.feed_message(EntityKind::TripUpdate).unwrap();
+        assert_eq!(trip_updates.entity.len(), 2);
+        assert!(trip_updates.header.timestamp.is_some());
+        let vehicles = manager.feed_message(EntityKind::VehiclePosition).unwrap();
+        assert_eq!(vehicles.entity.len(), 1);
+        assert_eq!(vehicles.entity[0].id, "j");
+        assert_eq!(vehicles.entity[0].trip_update, None);
+        let alerts = manager.feed_message(EntityKind::Alert).unwrap();
+        assert!(alerts.entity.is_empty());
+    }
 }
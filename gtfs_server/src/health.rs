@@ -0,0 +1,153 @@
+//! `/healthz`, whether the server is up, and `/readyz`, whether it can
+//! answer requests.
+
+use chrono::prelude::*;
+use diesel::prelude::*;
+use serde::Serialize;
+use std::convert::Infallible;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use warp::http::StatusCode;
+
+use crate::database::ConnectionPool;
+use crate::error::ServerError;
+use crate::gtfs_data::RealtimeUpdateManager;
+use crate::schema;
+
+/// How long `/readyz` waits for a database connection.
+const DATABASE_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(Serialize, Debug)]
+struct Readiness {
+    ready: bool,
+    database: DatabaseCheck,
+    realtime: RealtimeCheck,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+struct DatabaseCheck {
+    ok: bool,
+    /// the number of GTFS feeds imported
+    feeds: Option<i64>,
+    error: Option<String>,
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+struct RealtimeCheck {
+    ok: bool,
+    /// when the latest realtime data was created
+    timestamp: Option<DateTime<Utc>>,
+    age_secs: Option<i64>,
+    max_age_secs: u64,
+}
+
+pub fn healthz() -> impl warp::Reply {
+    warp::reply::json(&serde_json::json!({ "status": "ok" }))
+}
+
+/// Ready if a database connection can be made, at least one feed has been
+/// imported, and the realtime data is no older than `max_age`. Responds with
+/// 503 if not.
+pub async fn readyz(
+    pool: ConnectionPool,
+    realtime_manager: Arc<Mutex<RealtimeUpdateManager>>,
+    max_age: Duration,
+) -> Result<impl warp::Reply, Infallible> {
+    let database = check_database(pool).await;
+    let timestamp = realtime_manager.lock().unwrap().timestamp();
+    let realtime = check_realtime(timestamp, max_age, Utc::now());
+    let ready = database.ok && realtime.ok;
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    let r = Readiness {
+        ready,
+        database,
+        realtime,
+    };
+    Ok(warp::reply::with_status(warp::reply::json(&r), status))
+}
+
+async fn check_database(pool: ConnectionPool) -> DatabaseCheck {
+    let feeds = tokio::task::spawn_blocking(move || {
+        let connection = pool.get_timeout(DATABASE_TIMEOUT)?;
+        let feeds = schema::feed::table.count().get_result::<i64>(&connection)?;
+        Ok::<_, ServerError>(feeds)
+    })
+    .await
+    .map_err(ServerError::from)
+    .and_then(|r| r);
+    match feeds {
+        Ok(0) => DatabaseCheck {
+            ok: false,
+            feeds: Some(0),
+            error: Some("No feeds have been imported".to_string()),
+        },
+        Ok(feeds) => DatabaseCheck {
+            ok: true,
+            feeds: Some(feeds),
+            error: None,
+        },
+        Err(e) => DatabaseCheck {
+            ok: false,
+            feeds: None,
+            error: Some(e.to_string()),
+        },
+    }
+}
+
+fn check_realtime(
+    timestamp: Option<DateTime<Utc>>,
+    max_age: Duration,
+    now: DateTime<Utc>,
+) -> RealtimeCheck {
+    let age_secs = timestamp.map(|t| (now - t).num_seconds());
+    RealtimeCheck {
+        ok: matches!(age_secs, Some(age) if age <= max_age.as_secs() as i64),
+        timestamp,
+        age_secs,
+        max_age_secs: max_age.as_secs(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn realtime() {
+        let now = Utc.with_ymd_and_hms(2020, 5, 1, 12, 0, 0).unwrap();
+        let max_age = Duration::from_secs(300);
+        let check = |age: Option<i64>| {
+            check_realtime(
+                age.map(|a| now - chrono::Duration::seconds(a)),
+                max_age,
+                now,
+            )
+        };
+
+        assert!(check(Some(60)).ok);
+        assert!(check(Some(300)).ok);
+        assert_eq!(
+            check(Some(301)),
+            RealtimeCheck {
+                ok: false,
+                timestamp: Some(Utc.with_ymd_and_hms(2020, 5, 1, 11, 54, 59).unwrap()),
+                age_secs: Some(301),
+                max_age_secs: 300,
+            }
+        );
+        // nothing has been fetched yet
+        assert_eq!(
+            check(None),
+            RealtimeCheck {
+                ok: false,
+                timestamp: None,
+                age_secs: None,
+                max_age_secs: 300,
+            }
+        );
+    }
+}
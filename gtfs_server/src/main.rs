@@ -2,24 +2,37 @@
 extern crate diesel;
 
 mod api_fetcher;
+mod config;
 mod database;
+mod error;
 mod gtfs_data;
+mod health;
+mod metrics;
 mod model;
 mod protobuf;
 mod schema;
+mod timetable;
+mod vehicles;
 
-use log::{debug, info};
+use log::{debug, error, info};
 use serde::Deserialize;
+use warp::sse::ServerSentEvent;
 use warp::Filter;
 
-use crate::gtfs_data::{RealtimeQueryKey, RealtimeUpdate, RealtimeUpdateManager};
+use crate::gtfs_data::{EntityKind, RealtimeQueryKey, RealtimeUpdate, RealtimeUpdateManager};
 use chrono::prelude::*;
+use config::{Config, StopTimesConfig, StopTimesSource};
 use database::ConnectionPool;
 use dotenv::dotenv;
+use error::ServerError;
+use metrics::Metrics;
+use timetable::SharedTimetable;
+use vehicles::Vehicles;
 
 use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Clone, Copy)]
 struct StopTimesParams {
     range_start_mins: Option<u32>,
     range_end_mins: Option<u32>,
@@ -28,80 +41,372 @@ struct StopTimesParams {
 #[tokio::main]
 async fn main() {
     dotenv().ok(); // IMPORTANT
-    env_logger::init();
+    let config = Config::load().unwrap_or_else(|e| {
+        eprintln!("Invalid configuration: {}", e);
+        std::process::exit(1);
+    });
+
+    let mut logger = env_logger::Builder::new();
+    if let Some(filters) = &config.log {
+        logger.parse_filters(filters);
+    }
+    logger.init();
 
     println!("Starting web server");
 
-    let pool = database::create_connection_pool();
+    let pool = database::create_connection_pool(&config.database);
     info!("Created database connection pool");
 
-    // pass in a database connection pool
-    let data = warp::any().map(move || pool.clone());
-
     let realtime_manager = RealtimeUpdateManager::new();
     let arc_mutex = Arc::new(Mutex::new(realtime_manager));
     let arc_mutex_clone = arc_mutex.clone();
 
     let rt_filter = warp::any().map(move || arc_mutex_clone.clone());
 
+    let vehicles = Arc::new(Mutex::new(Vehicles::new()));
+    let vehicles_clone = vehicles.clone();
+    let vehicles_filter = warp::any().map(move || vehicles_clone.clone());
+
+    // notified each time realtime data is fetched
+    let (realtime_updates, _) = broadcast::channel(1);
+    let realtime_updates_clone = realtime_updates.clone();
+    let realtime_updates_filter = warp::any().map(move || realtime_updates_clone.clone());
+
+    // departures are found in an in-memory copy of the timetable, or with a
+    // query for each request
+    let (timetable, reload_timetable) = match config.stop_times.source {
+        StopTimesSource::Memory => {
+            let (timetable, reload) = timetable::load_shared(&pool).await;
+            (Some(timetable), futures::future::Either::Left(reload))
+        }
+        StopTimesSource::Database => (
+            None,
+            futures::future::Either::Right(futures::future::pending()),
+        ),
+    };
+    let timetable_filter = warp::any().map(move || timetable.clone());
+
+    let stop_times_config = config.stop_times;
+    let stop_times_config_filter = warp::any().map(move || stop_times_config);
+
+    let metrics = Metrics::new();
+    let metrics_clone = metrics.clone();
+    let metrics_filter = warp::any().map(move || metrics_clone.clone());
+
+    // pass in a database connection pool
+    let data = warp::any().map(move || pool.clone());
+
+    // stop/{code}
+    let stop_info = warp::any()
+        .and(data.clone())
+        .and(warp::path!("stop" / String))
+        .and_then(fetch_stops);
+
     // stop/{code}/..
     let stop = warp::any()
-        .and(data)
-        .and(rt_filter)
+        .and(data.clone())
+        .and(rt_filter.clone())
+        .and(timetable_filter)
+        .and(stop_times_config_filter)
         .and(warp::path!("stop" / String / ..));
 
     // stop/{code}/times
     let times = stop
-        .and(warp::path("times"))
+        .clone()
+        .and(warp::path!("times"))
         .and(warp::query::query()) // fetch query parameters from url
         .and_then(fetch_stop_times);
 
-    futures::future::join(
-        warp::serve(times).run(([127, 0, 0, 1], 6789)),
-        api_fetcher::fetch_data(arc_mutex.clone()),
+    // stop/{code}/times/stream
+    let times_stream = stop
+        .and(realtime_updates_filter)
+        .and(warp::path!("times" / "stream"))
+        .and(warp::query::query())
+        .and_then(stream_stop_times);
+
+    // vehicles, a websocket of vehicle positions
+    let vehicle_positions = warp::path!("vehicles")
+        .and(warp::ws())
+        .and(vehicles_filter)
+        .map(|ws: warp::ws::Ws, vehicles| {
+            ws.on_upgrade(move |socket| vehicles::client_connected(socket, vehicles))
+        });
+
+    // gtfs-rt/{trip-updates,vehicle-positions,alerts}.pb, the realtime feed
+    let realtime_feed = warp::path("gtfs-rt")
+        .and(
+            warp::path!("trip-updates.pb")
+                .map(|| EntityKind::TripUpdate)
+                .or(warp::path!("vehicle-positions.pb").map(|| EntityKind::VehiclePosition))
+                .unify()
+                .or(warp::path!("alerts.pb").map(|| EntityKind::Alert))
+                .unify(),
+        )
+        .and(rt_filter.clone())
+        .and(warp::query::query())
+        .and_then(fetch_realtime_feed);
+
+    // metrics, in Prometheus' format
+    let metrics_route = warp::path!("metrics")
+        .and(data.clone())
+        .and(rt_filter.clone())
+        .and(metrics_filter)
+        .map(
+            |pool: ConnectionPool,
+             realtime_manager: Arc<Mutex<RealtimeUpdateManager>>,
+             metrics: Metrics| {
+                metrics.reply(&pool, &realtime_manager.lock().unwrap())
+            },
+        );
+
+    // healthz and readyz, for checking the server from outside
+    let healthz = warp::path!("healthz").map(health::healthz);
+    let realtime_max_age = config.realtime.max_age();
+    let readyz = warp::path!("readyz")
+        .and(data)
+        .and(rt_filter)
+        .and(warp::any().map(move || realtime_max_age))
+        .and_then(health::readyz);
+
+    let routes = times
+        .or(times_stream)
+        .or(stop_info)
+        .or(vehicle_positions)
+        .or(realtime_feed)
+        .or(metrics_route)
+        .or(healthz)
+        .or(readyz)
+        .recover(error::handle_rejection)
+        .with(warp::log::custom({
+            let metrics = metrics.clone();
+            move |info| metrics.request(info)
+        }));
+
+    futures::future::join3(
+        warp::serve(routes).run(config.listen),
+        api_fetcher::fetch_data(
+            config.realtime,
+            arc_mutex.clone(),
+            vehicles,
+            realtime_updates,
+            metrics,
+        ),
+        reload_timetable,
     )
     .await;
 }
 
-#[derive(Debug)]
-enum ServerError {
-    DbError(diesel::result::Error),
-    TokioError(tokio::task::JoinError),
+/// The stops with a code. A stop code may be used in more than one feed.
+fn find_stops(
+    connection: &database::DbConnection,
+    stop_code: &str,
+) -> diesel::QueryResult<Vec<model::Stop>> {
+    use diesel::prelude::*;
+    use schema::stop::dsl;
+
+    dsl::stop
+        .filter(dsl::stop_code.eq(stop_code))
+        .select(model::STOP_COLUMNS)
+        .load(connection)
 }
-impl warp::reject::Reject for ServerError {}
 
-impl Into<warp::reject::Rejection> for ServerError {
-    fn into(self) -> warp::reject::Rejection {
-        warp::reject::custom(self)
+async fn fetch_stops(
+    pool: ConnectionPool,
+    stop_code: String,
+) -> Result<warp::reply::Json, warp::Rejection> {
+    let code = stop_code.clone();
+    let stops = tokio::task::spawn_blocking(move || {
+        let connection = pool.get()?;
+        Ok::<_, ServerError>(find_stops(&connection, &code)?)
+    })
+    .await
+    .map_err(ServerError::from)??;
+
+    if stops.is_empty() {
+        return Err(ServerError::UnknownStop(stop_code).into());
     }
+
+    #[derive(serde::Serialize, Debug)]
+    struct R {
+        stops: Vec<model::Stop>,
+    }
+    Ok(warp::reply::json(&R { stops }))
+}
+
+#[derive(Deserialize, Debug)]
+struct RealtimeFeedParams {
+    /// pb (the default) or json
+    format: Option<String>,
+}
+
+/// The entities of one kind in the realtime feeds, merged.
+async fn fetch_realtime_feed(
+    kind: EntityKind,
+    realtime_manager: Arc<Mutex<RealtimeUpdateManager>>,
+    params: RealtimeFeedParams,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    use prost::Message;
+    use warp::Reply;
+
+    let feed = realtime_manager
+        .lock()
+        .unwrap()
+        .feed_message(kind)
+        .ok_or(ServerError::NoRealtimeData)?;
+    match params.format.as_deref() {
+        None | Some("pb") => {
+            let mut bytes = Vec::with_capacity(feed.encoded_len());
+            feed.encode(&mut bytes)
+                .expect("the buffer has enough capacity");
+            let reply = warp::reply::with_header(bytes, "content-type", "application/x-protobuf");
+            Ok(reply.into_response())
+        }
+        Some("json") => Ok(warp::reply::json(&feed).into_response()),
+        Some(format) => Err(ServerError::UnknownFormat(format.to_string()).into()),
+    }
+}
+
+#[derive(serde::Serialize, Debug)]
+struct StopTimes {
+    // for client to get accurate UTC time
+    current_time: DateTime<Utc>,
+    /// the stops with the code, as in /stop/{code}
+    stops: Vec<model::Stop>,
+    trips: Vec<StopTime>,
+    /// if there are no trips, the first scheduled departure after the range
+    next_departure: Option<model::StopTimeByStop>,
+}
+
+#[derive(serde::Serialize, Debug)]
+struct StopTime {
+    base: model::StopTimeByStop,
+    realtime: Option<CombinedRealtimeUpdate>,
+}
+
+#[derive(serde::Serialize, Debug)]
+struct CombinedRealtimeUpdate {
+    departure_time: DateTime<Utc>,
+    #[serde(flatten)]
+    realtime_update: RealtimeUpdate,
 }
 
 async fn fetch_stop_times(
     pool: ConnectionPool,
     realtime_manager: Arc<Mutex<RealtimeUpdateManager>>,
+    timetable: Option<SharedTimetable>,
+    config: StopTimesConfig,
     stop_code: String,
     params: StopTimesParams,
 ) -> Result<warp::reply::Json, warp::Rejection> {
-    let connection = pool.get().unwrap();
+    let r = stop_times(pool, realtime_manager, timetable, config, stop_code, params)
+        .await
+        .map_err(warp::reject::custom)?;
+    Ok(warp::reply::json(&r))
+}
+
+/// Sends the departures from a stop as server-sent events, again each time
+/// realtime data is fetched.
+async fn stream_stop_times(
+    pool: ConnectionPool,
+    realtime_manager: Arc<Mutex<RealtimeUpdateManager>>,
+    timetable: Option<SharedTimetable>,
+    config: StopTimesConfig,
+    stop_code: String,
+    realtime_updates: broadcast::Sender<()>,
+    params: StopTimesParams,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let updates = realtime_updates.subscribe();
+    // errors before the stream starts, like an unknown stop, are responses
+    let first = stop_times(
+        pool.clone(),
+        realtime_manager.clone(),
+        timetable.clone(),
+        config,
+        stop_code.clone(),
+        params,
+    )
+    .await?;
+    let events = futures::stream::unfold((updates, Some(first)), move |(mut updates, first)| {
+        let (pool, realtime_manager, timetable, stop_code) = (
+            pool.clone(),
+            realtime_manager.clone(),
+            timetable.clone(),
+            stop_code.clone(),
+        );
+        async move {
+            let r = match first {
+                Some(first) => Ok(first),
+                None => {
+                    match updates.recv().await {
+                        // missed updates don't matter, as everything is sent again
+                        Ok(()) | Err(broadcast::RecvError::Lagged(_)) => (),
+                        Err(broadcast::RecvError::Closed) => return None,
+                    }
+                    stop_times(pool, realtime_manager, timetable, config, stop_code, params).await
+                }
+            };
+            let event = match r {
+                Ok(r) => warp::sse::json(r).into_a(),
+                Err(e) => {
+                    error!("{}", e);
+                    (warp::sse::event("error"), warp::sse::json(e.body())).into_b()
+                }
+            };
+            Some((Ok::<_, std::convert::Infallible>(event), (updates, None)))
+        }
+    });
+    Ok(warp::sse::reply(warp::sse::keep_alive().stream(events)))
+}
+
+async fn stop_times(
+    pool: ConnectionPool,
+    realtime_manager: Arc<Mutex<RealtimeUpdateManager>>,
+    timetable: Option<SharedTimetable>,
+    config: StopTimesConfig,
+    stop_code: String,
+    params: StopTimesParams,
+) -> Result<StopTimes, ServerError> {
     use diesel::pg::types::sql_types::Timestamptz;
     use diesel::prelude::*;
     use diesel::sql_types::Text;
 
     let now = chrono::Utc::now();
-    // TODO 30 is a magic value to handle delays
-    let a = now - chrono::Duration::minutes(params.range_start_mins.unwrap_or(2).into());
-    let b = now + chrono::Duration::minutes(params.range_end_mins.unwrap_or(720).into());
+    let range_start_mins = params.range_start_mins.unwrap_or(config.range_start_mins);
+    let range_end_mins = params.range_end_mins.unwrap_or(config.range_end_mins);
+    let a = now - chrono::Duration::minutes(range_start_mins.into());
+    let b = now + chrono::Duration::minutes(range_end_mins.into());
+    // scheduled departures before the range may be delayed into it
+    let scheduled_from = a - chrono::Duration::minutes(config.delay_slack_mins.into());
     debug!("now: {}, from -{} to {}", now, a, b);
 
-    let x: Vec<model::StopTimeByStop> = tokio::task::spawn_blocking(move || {
-        let r = diesel::sql_query(include_str!("sql_queries/stop_times.sql"))
-            .bind::<Timestamptz, _>(a - chrono::Duration::minutes(30))
-            .bind::<Timestamptz, _>(b)
-            .bind::<Text, _>(stop_code)
-            .load(&connection)
-            .map_err(|e| warp::reject::custom(ServerError::DbError(e)))?;
-        Ok::<_, warp::reject::Rejection>(r)
-    }).await.map_err(|e| warp::reject::custom(ServerError::TokioError(e)))??;
+    let (stops, x) = match &timetable {
+        Some(timetable) => {
+            let timetable = timetable.read().unwrap();
+            let stops = timetable.stops(&stop_code);
+            let x = timetable.departures(&stop_code, scheduled_from, b);
+            (stops, x)
+        }
+        None => {
+            let (pool, stop_code) = (pool.clone(), stop_code.clone());
+            tokio::task::spawn_blocking(move || {
+                let connection = pool.get()?;
+                let stops = find_stops(&connection, &stop_code)?;
+                if stops.is_empty() {
+                    return Ok((stops, vec![]));
+                }
+                let x = diesel::sql_query(include_str!("sql_queries/stop_times.sql"))
+                    .bind::<Timestamptz, _>(scheduled_from)
+                    .bind::<Timestamptz, _>(b)
+                    .bind::<Text, _>(stop_code)
+                    .load(&connection)?;
+                Ok::<_, ServerError>((stops, x))
+            })
+            .await??
+        }
+    };
+    if stops.is_empty() {
+        return Err(ServerError::UnknownStop(stop_code));
+    }
 
     let realtime = (*realtime_manager.lock().unwrap()).get_realtime_updates(x.iter().map(|y| {
         RealtimeQueryKey {
@@ -111,25 +416,6 @@ async fn fetch_stop_times(
         }
     }));
 
-    #[derive(serde::Serialize, Debug)]
-    struct T {
-        base: model::StopTimeByStop,
-        realtime: Option<CombinedRealtimeUpdate>,
-    }
-
-    #[derive(serde::Serialize, Debug)]
-    struct R {
-        // for client to get accurate UTC time
-        current_time: DateTime<Utc>,
-        trips: Vec<T>,
-    }
-    #[derive(serde::Serialize, Debug)]
-    struct CombinedRealtimeUpdate {
-        departure_time: DateTime<Utc>,
-        #[serde(flatten)]
-        realtime_update: RealtimeUpdate,
-    }
-
     let trips = x
         .into_iter()
         .zip(realtime)
@@ -140,7 +426,7 @@ async fn fetch_stop_times(
                 if departure_time > b || departure_time < a {
                     return None;
                 }
-                Some(T {
+                Some(StopTime {
                     base,
                     realtime: Some(CombinedRealtimeUpdate {
                         realtime_update: realtime,
@@ -152,16 +438,64 @@ async fn fetch_stop_times(
                 if base.departure_time > b || base.departure_time < a {
                     return None;
                 }
-                Some(T {
+                Some(StopTime {
                     base,
                     realtime: None,
                 })
             }
         })
-        .collect::<Vec<T>>();
+        .collect::<Vec<StopTime>>();
 
-    Ok(warp::reply::json(&R {
+    let next_departure = if trips.is_empty() {
+        let within = chrono::Duration::days(config.next_departure_within_days.into());
+        next_departure(pool, timetable, stop_code, b, within).await?
+    } else {
+        None
+    };
+
+    Ok(StopTimes {
         current_time: now,
+        stops,
         trips,
-    }))
+        next_departure,
+    })
+}
+
+/// The first scheduled departure from the stops with a code after `after`,
+/// up to `within` later.
+async fn next_departure(
+    pool: ConnectionPool,
+    timetable: Option<SharedTimetable>,
+    stop_code: String,
+    after: DateTime<Utc>,
+    within: chrono::Duration,
+) -> Result<Option<model::StopTimeByStop>, ServerError> {
+    use diesel::pg::types::sql_types::Timestamptz;
+    use diesel::prelude::*;
+    use diesel::sql_types::Text;
+
+    let until = after + within;
+    match timetable {
+        Some(timetable) => Ok(timetable
+            .read()
+            .unwrap()
+            .first_departure(&stop_code, after, until)),
+        None => {
+            tokio::task::spawn_blocking(move || {
+                let connection = pool.get()?;
+                let departure = diesel::sql_query(concat!(
+                    "select * from (",
+                    include_str!("sql_queries/stop_times.sql"),
+                    ") departures order by departure_time limit 1"
+                ))
+                .bind::<Timestamptz, _>(after)
+                .bind::<Timestamptz, _>(until)
+                .bind::<Text, _>(stop_code)
+                .get_result(&connection)
+                .optional()?;
+                Ok(departure)
+            })
+            .await?
+        }
+    }
 }
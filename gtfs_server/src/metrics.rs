@@ -0,0 +1,281 @@
+//! Prometheus metrics, served at `/metrics`.
+
+use prometheus::{
+    Encoder, GaugeVec, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, IntGaugeVec, Opts,
+    Registry, TextEncoder,
+};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::database::ConnectionPool;
+use crate::gtfs_data::{EntityKind, RealtimeUpdateManager};
+use crate::protobuf::gtfs_realtime::FeedMessage;
+
+/// The metrics, shared by the routes and the realtime fetcher.
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+    requests: IntCounterVec,
+    request_duration: HistogramVec,
+    pool_connections: IntGauge,
+    pool_idle_connections: IntGauge,
+    pool_max_size: IntGauge,
+    fetches: IntCounterVec,
+    fetch_duration: HistogramVec,
+    feed_age: GaugeVec,
+    /// the header timestamp of the latest fetch of each feed
+    feed_timestamps: Arc<Mutex<HashMap<usize, u64>>>,
+    entities: IntGaugeVec,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new();
+        let metrics = Metrics {
+            requests: IntCounterVec::new(
+                Opts::new("http_requests_total", "Requests by route and status"),
+                &["route", "status"],
+            )
+            .unwrap(),
+            request_duration: HistogramVec::new(
+                HistogramOpts::new(
+                    "http_request_duration_seconds",
+                    "Time taken to respond to requests, by route",
+                ),
+                &["route"],
+            )
+            .unwrap(),
+            pool_connections: IntGauge::new(
+                "db_pool_connections",
+                "Database connections, idle or in use",
+            )
+            .unwrap(),
+            pool_idle_connections: IntGauge::new(
+                "db_pool_idle_connections",
+                "Idle database connections",
+            )
+            .unwrap(),
+            pool_max_size: IntGauge::new(
+                "db_pool_max_size",
+                "The most database connections in the pool",
+            )
+            .unwrap(),
+            fetches: IntCounterVec::new(
+                Opts::new(
+                    "realtime_fetches_total",
+                    "Fetches of realtime feeds, by feed and result (success or failure)",
+                ),
+                &["feed", "result"],
+            )
+            .unwrap(),
+            fetch_duration: HistogramVec::new(
+                HistogramOpts::new(
+                    "realtime_fetch_duration_seconds",
+                    "Time taken to fetch realtime feeds, by feed",
+                ),
+                &["feed"],
+            )
+            .unwrap(),
+            feed_age: GaugeVec::new(
+                Opts::new(
+                    "realtime_feed_age_seconds",
+                    "Seconds since the timestamp of the latest fetch of each feed",
+                ),
+                &["feed"],
+            )
+            .unwrap(),
+            feed_timestamps: Arc::new(Mutex::new(HashMap::new())),
+            entities: IntGaugeVec::new(
+                Opts::new(
+                    "realtime_entities",
+                    "Entities in the merged realtime feed, by kind",
+                ),
+                &["kind"],
+            )
+            .unwrap(),
+            registry,
+        };
+        let r = &metrics.registry;
+        r.register(Box::new(metrics.requests.clone())).unwrap();
+        r.register(Box::new(metrics.request_duration.clone()))
+            .unwrap();
+        r.register(Box::new(metrics.pool_connections.clone()))
+            .unwrap();
+        r.register(Box::new(metrics.pool_idle_connections.clone()))
+            .unwrap();
+        r.register(Box::new(metrics.pool_max_size.clone())).unwrap();
+        r.register(Box::new(metrics.fetches.clone())).unwrap();
+        r.register(Box::new(metrics.fetch_duration.clone()))
+            .unwrap();
+        r.register(Box::new(metrics.feed_age.clone())).unwrap();
+        r.register(Box::new(metrics.entities.clone())).unwrap();
+        metrics
+    }
+
+    /// Counts a response, for `warp::log::custom`.
+    pub fn request(&self, info: warp::log::Info) {
+        let route = route(info.path());
+        self.requests
+            .with_label_values(&[route, info.status().as_str()])
+            .inc();
+        self.request_duration
+            .with_label_values(&[route])
+            .observe(info.elapsed().as_secs_f64());
+    }
+
+    /// `feed` is the index of the feed in the config. Feeds aren't labelled
+    /// by url, as urls may contain api keys.
+    pub fn fetch_succeeded(&self, feed: usize, duration: Duration, message: &FeedMessage) {
+        let label = feed.to_string();
+        self.fetches.with_label_values(&[&label, "success"]).inc();
+        self.fetch_duration
+            .with_label_values(&[&label])
+            .observe(duration.as_secs_f64());
+        let mut timestamps = self.feed_timestamps.lock().unwrap();
+        match message.header.timestamp {
+            Some(t) => timestamps.insert(feed, t),
+            None => timestamps.remove(&feed),
+        };
+    }
+
+    pub fn fetch_failed(&self, feed: usize, duration: Duration) {
+        let label = feed.to_string();
+        self.fetches.with_label_values(&[&label, "failure"]).inc();
+        self.fetch_duration
+            .with_label_values(&[&label])
+            .observe(duration.as_secs_f64());
+    }
+
+    /// Stops reporting the age of feeds which are no longer configured, when
+    /// there are `count` feeds.
+    pub fn retain_feeds(&self, count: usize) {
+        self.feed_timestamps
+            .lock()
+            .unwrap()
+            .retain(|&feed, _| feed < count);
+    }
+
+    /// The metrics in Prometheus' text format, with the pool and realtime
+    /// data as they are now.
+    pub fn reply(
+        &self,
+        pool: &ConnectionPool,
+        realtime_manager: &RealtimeUpdateManager,
+    ) -> impl warp::Reply {
+        let state = pool.state();
+        self.pool_connections.set(state.connections.into());
+        self.pool_idle_connections
+            .set(state.idle_connections.into());
+        self.pool_max_size.set(pool.max_size().into());
+        self.set_realtime(realtime_manager);
+        warp::reply::with_header(
+            self.encode_registry(),
+            "content-type",
+            TextEncoder::new().format_type(),
+        )
+    }
+
+    fn set_realtime(&self, realtime_manager: &RealtimeUpdateManager) {
+        let now = chrono::Utc::now().timestamp();
+        self.feed_age.reset();
+        for (feed, &timestamp) in self.feed_timestamps.lock().unwrap().iter() {
+            self.feed_age
+                .with_label_values(&[&feed.to_string()])
+                .set((now - timestamp as i64) as f64);
+        }
+        for (kind, label) in &[
+            (EntityKind::TripUpdate, "trip_update"),
+            (EntityKind::VehiclePosition, "vehicle_position"),
+            (EntityKind::Alert, "alert"),
+        ] {
+            self.entities
+                .with_label_values(&[label])
+                .set(realtime_manager.entity_count(*kind) as i64);
+        }
+    }
+
+    fn encode_registry(&self) -> Vec<u8> {
+        let mut buffer = vec![];
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .expect("metrics should be encoded");
+        buffer
+    }
+}
+
+/// The route a path is for, so that each stop code isn't a separate label.
+fn route(path: &str) -> &'static str {
+    let segments = path.trim_start_matches('/').split('/').collect::<Vec<_>>();
+    match segments.as_slice() {
+        ["stop", _] => "/stop/{code}",
+        ["stop", _, "times"] => "/stop/{code}/times",
+        ["stop", _, "times", "stream"] => "/stop/{code}/times/stream",
+        ["vehicles"] => "/vehicles",
+        ["gtfs-rt", "trip-updates.pb"] => "/gtfs-rt/trip-updates.pb",
+        ["gtfs-rt", "vehicle-positions.pb"] => "/gtfs-rt/vehicle-positions.pb",
+        ["gtfs-rt", "alerts.pb"] => "/gtfs-rt/alerts.pb",
+        ["metrics"] => "/metrics",
+        ["healthz"] => "/healthz",
+        ["readyz"] => "/readyz",
+        _ => "other",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protobuf::gtfs_realtime::{FeedEntity, FeedHeader, VehiclePosition};
+
+    #[test]
+    fn routes() {
+        assert_eq!(route("/stop/1234"), "/stop/{code}");
+        assert_eq!(route("/stop/1234/times"), "/stop/{code}/times");
+        assert_eq!(
+            route("/stop/1234/times/stream"),
+            "/stop/{code}/times/stream"
+        );
+        assert_eq!(route("/gtfs-rt/alerts.pb"), "/gtfs-rt/alerts.pb");
+        assert_eq!(route("/stop/1234/other"), "other");
+        assert_eq!(route("/"), "other");
+    }
+
+    #[test]
+    fn realtime() {
+        let metrics = Metrics::new();
+        let now = chrono::Utc::now().timestamp() as u64;
+        let feed = FeedMessage {
+            header: FeedHeader {
+                gtfs_realtime_version: "2.0".into(),
+                incrementality: None,
+                timestamp: Some(now - 40),
+            },
+            entity: vec![FeedEntity {
+                id: "1".into(),
+                vehicle: Some(VehiclePosition::default()),
+                ..Default::default()
+            }],
+        };
+        metrics.fetch_succeeded(0, Duration::from_millis(200), &feed);
+        metrics.fetch_succeeded(1, Duration::from_millis(200), &feed);
+        metrics.fetch_failed(0, Duration::from_secs(1));
+        metrics.retain_feeds(1);
+        let mut realtime_manager = RealtimeUpdateManager::new();
+        realtime_manager.load_feed(feed);
+        metrics.set_realtime(&realtime_manager);
+
+        let text = String::from_utf8(metrics.encode_registry()).unwrap();
+        let lines = text.lines().collect::<Vec<_>>();
+        assert!(lines.contains(&r#"realtime_fetches_total{feed="0",result="success"} 1"#));
+        assert!(lines.contains(&r#"realtime_fetches_total{feed="0",result="failure"} 1"#));
+        assert!(lines.contains(&r#"realtime_fetch_duration_seconds_count{feed="0"} 2"#));
+        let age = lines
+            .iter()
+            .filter(|l| l.starts_with("realtime_feed_age_seconds{"))
+            .collect::<Vec<_>>();
+        assert_eq!(age.len(), 1);
+        assert!(age[0].starts_with(r#"realtime_feed_age_seconds{feed="0"} 4"#));
+        assert!(lines.contains(&r#"realtime_entities{kind="vehicle_position"} 1"#));
+        assert!(lines.contains(&r#"realtime_entities{kind="trip_update"} 0"#));
+    }
+}
@@ -9,7 +9,7 @@ use diesel::sql_types::{Bool, Integer, Nullable, Text};
 use diesel::{Identifiable, Queryable};
 use serde::Serialize;
 
-#[derive(QueryableByName, Debug, Serialize)]
+#[derive(QueryableByName, Debug, Serialize, PartialEq)]
 pub struct StopTimeByStop {
     #[sql_type = "Text"]
     pub stop_id: String,
@@ -31,4 +31,54 @@ pub struct StopTimeByStop {
     pub route_long_name: String,
     #[sql_type = "Integer"]
     pub route_type: i32,
+    #[sql_type = "Nullable<Integer>"]
+    pub wheelchair_accessible: Option<i32>,
+    #[sql_type = "Nullable<Integer>"]
+    pub bikes_allowed: Option<i32>,
+    #[sql_type = "Nullable<Integer>"]
+    pub wheelchair_boarding: Option<i32>,
+    /// the stop may be in another feed linked to the requested stop
+    #[sql_type = "Integer"]
+    pub feed_id: i32,
+    #[sql_type = "Text"]
+    pub agency_name: String,
+}
+
+#[derive(Queryable, Debug, Serialize, Clone, PartialEq)]
+pub struct Stop {
+    pub feed_id: i32,
+    pub stop_id: String,
+    pub stop_code: Option<String>,
+    pub stop_name: String,
+    pub stop_lat: f64,
+    pub stop_lon: f64,
+    pub parent_station: Option<String>,
+    pub location_type: Option<i32>,
+    pub wheelchair_boarding: Option<i32>,
+    pub platform_code: Option<String>,
 }
+
+/// The columns of `stop` loaded into a `Stop`.
+pub const STOP_COLUMNS: (
+    stop::feed_id,
+    stop::stop_id,
+    stop::stop_code,
+    stop::stop_name,
+    stop::stop_lat,
+    stop::stop_lon,
+    stop::parent_station,
+    stop::location_type,
+    stop::wheelchair_boarding,
+    stop::platform_code,
+) = (
+    stop::feed_id,
+    stop::stop_id,
+    stop::stop_code,
+    stop::stop_name,
+    stop::stop_lat,
+    stop::stop_lon,
+    stop::parent_station,
+    stop::location_type,
+    stop::wheelchair_boarding,
+    stop::platform_code,
+);
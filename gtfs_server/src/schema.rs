@@ -7,6 +7,8 @@ table! {
         agency_timezone -> Text,
         agency_lang -> Nullable<Text>,
         agency_phone -> Nullable<Text>,
+        agency_fare_url -> Nullable<Text>,
+        agency_email -> Nullable<Text>,
     }
 }
 
@@ -38,6 +40,11 @@ table! {
 table! {
     feed (feed_id) {
         feed_id -> Int4,
+        source -> Nullable<Text>,
+        etag -> Nullable<Text>,
+        last_modified -> Nullable<Text>,
+        imported_at -> Timestamptz,
+        content_hash -> Nullable<Text>,
     }
 }
 
@@ -51,6 +58,19 @@ table! {
         route_type -> Int4,
         route_color -> Nullable<Text>,
         route_text_color -> Nullable<Text>,
+        route_desc -> Nullable<Text>,
+        route_url -> Nullable<Text>,
+        route_sort_order -> Nullable<Int4>,
+        continuous_pickup -> Nullable<Int4>,
+        continuous_drop_off -> Nullable<Int4>,
+    }
+}
+
+table! {
+    service_date (feed_id, service_id, date) {
+        feed_id -> Int4,
+        service_id -> Text,
+        date -> Date,
     }
 }
 
@@ -77,6 +97,21 @@ table! {
         zone_id -> Nullable<Text>,
         parent_station -> Nullable<Text>,
         location_type -> Nullable<Int4>,
+        stop_url -> Nullable<Text>,
+        stop_timezone -> Nullable<Text>,
+        wheelchair_boarding -> Nullable<Int4>,
+        level_id -> Nullable<Text>,
+        platform_code -> Nullable<Text>,
+    }
+}
+
+table! {
+    stop_link (feed_id, stop_id, linked_feed_id, linked_stop_id) {
+        feed_id -> Int4,
+        stop_id -> Text,
+        linked_feed_id -> Int4,
+        linked_stop_id -> Text,
+        manual -> Bool,
     }
 }
 
@@ -89,9 +124,12 @@ table! {
         stop_id -> Text,
         stop_sequence -> Int4,
         stop_headsign -> Nullable<Text>,
-        shape_dist_traveled -> Nullable<Int4>,
+        shape_dist_traveled -> Nullable<Float8>,
         pickup_type -> Nullable<Int4>,
         drop_off_type -> Nullable<Int4>,
+        continuous_pickup -> Nullable<Int4>,
+        continuous_drop_off -> Nullable<Int4>,
+        timepoint -> Nullable<Int4>,
     }
 }
 
@@ -106,12 +144,15 @@ table! {
         direction_id -> Nullable<Bool>,
         shape_id -> Nullable<Text>,
         block_id -> Nullable<Text>,
+        wheelchair_accessible -> Nullable<Int4>,
+        bikes_allowed -> Nullable<Int4>,
     }
 }
 
 joinable!(calendar -> feed (feed_id));
 joinable!(calendar_date -> feed (feed_id));
 joinable!(route -> feed (feed_id));
+joinable!(service_date -> feed (feed_id));
 joinable!(shape -> feed (feed_id));
 joinable!(stop -> feed (feed_id));
 joinable!(stop_time -> feed (feed_id));
@@ -122,8 +163,10 @@ allow_tables_to_appear_in_same_query!(
     calendar_date,
     feed,
     route,
+    service_date,
     shape,
     stop,
+    stop_link,
     stop_time,
     trip,
 );
@@ -0,0 +1,868 @@
+//! An in-memory copy of the static timetable, answering departures queries
+//! without going to the database. Used instead of `sql_queries/stop_times.sql`
+//! when `STOP_TIMES_SOURCE=memory`, and reloaded when the feeds change.
+
+use chrono::prelude::*;
+use chrono::Duration;
+use chrono_tz::Tz;
+use diesel::prelude::*;
+use diesel::sql_types::Text;
+use log::{error, info, warn};
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, RwLock};
+
+use crate::database::{ConnectionPool, DbConnection};
+use crate::model::{self, StopTimeByStop};
+use crate::schema;
+
+/// How often the feed table is checked for changes.
+const RELOAD_CHECK_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+pub type SharedTimetable = Arc<RwLock<Timetable>>;
+
+#[derive(Queryable)]
+pub struct StopLinkRow {
+    pub feed_id: i32,
+    pub stop_id: String,
+    pub linked_feed_id: i32,
+    pub linked_stop_id: String,
+}
+
+#[derive(Queryable)]
+pub struct AgencyRow {
+    pub feed_id: i32,
+    pub agency_id: String,
+    pub agency_name: String,
+    pub agency_timezone: String,
+}
+
+#[derive(Queryable)]
+pub struct RouteRow {
+    pub feed_id: i32,
+    pub route_id: String,
+    pub agency_id: String,
+    pub route_short_name: Option<String>,
+    pub route_long_name: Option<String>,
+    pub route_type: i32,
+}
+
+#[derive(Queryable)]
+pub struct TripRow {
+    pub feed_id: i32,
+    pub trip_id: String,
+    pub route_id: String,
+    pub service_id: String,
+    pub direction_id: Option<bool>,
+    pub trip_headsign: Option<String>,
+    pub wheelchair_accessible: Option<i32>,
+    pub bikes_allowed: Option<i32>,
+}
+
+#[derive(Queryable)]
+pub struct StopTimeRow {
+    pub feed_id: i32,
+    pub trip_id: String,
+    pub stop_id: String,
+    pub stop_sequence: i32,
+    pub departure_time: i32,
+    pub pickup_type: Option<i32>,
+}
+
+#[derive(Queryable)]
+pub struct ServiceDateRow {
+    pub feed_id: i32,
+    pub service_id: String,
+    pub date: NaiveDate,
+}
+
+/// The rows of a timetable, as loaded from the database.
+#[derive(Default)]
+pub struct TimetableRows {
+    pub stops: Vec<model::Stop>,
+    pub stop_links: Vec<StopLinkRow>,
+    pub agencies: Vec<AgencyRow>,
+    pub routes: Vec<RouteRow>,
+    pub trips: Vec<TripRow>,
+    pub stop_times: Vec<StopTimeRow>,
+    pub service_dates: Vec<ServiceDateRow>,
+}
+
+struct Stop {
+    info: model::Stop,
+    /// platforms of a station
+    children: Vec<usize>,
+    /// stops in other feeds at the same place
+    links: Vec<usize>,
+    /// sorted by departure_time, without those where pickup isn't available
+    departures: Vec<Departure>,
+}
+
+struct Departure {
+    departure_time: i32,
+    stop_sequence: i32,
+    trip: usize,
+}
+
+struct Agency {
+    name: String,
+    timezone: Tz,
+}
+
+struct Route {
+    agency: usize,
+    short_name: Option<String>,
+    long_name: String,
+    route_type: i32,
+}
+
+struct Trip {
+    trip_id: String,
+    route: usize,
+    service: usize,
+    direction_id: Option<bool>,
+    headsign: Option<String>,
+    wheelchair_accessible: Option<i32>,
+    bikes_allowed: Option<i32>,
+}
+
+/// A departure found by `Timetable::scan_departures`, as indices.
+struct FoundDeparture {
+    stop: usize,
+    /// index in the stop's departures
+    departure: usize,
+    agency: usize,
+    departure_time: DateTime<Utc>,
+    service_date: NaiveDate,
+}
+
+#[derive(Default)]
+pub struct Timetable {
+    stops: Vec<Stop>,
+    stops_by_code: HashMap<String, Vec<usize>>,
+    agencies: Vec<Agency>,
+    agencies_by_feed: HashMap<i32, Vec<usize>>,
+    routes: Vec<Route>,
+    trips: Vec<Trip>,
+    /// the days each service runs, indexed by Trip::service
+    service_dates: Vec<HashSet<NaiveDate>>,
+}
+
+impl Timetable {
+    pub fn new(rows: TimetableRows) -> Self {
+        let mut t = Timetable::default();
+
+        let mut stop_index = HashMap::new();
+        for s in rows.stops {
+            stop_index.insert((s.feed_id, s.stop_id.clone()), t.stops.len());
+            t.stops.push(Stop {
+                info: s,
+                children: vec![],
+                links: vec![],
+                departures: vec![],
+            });
+        }
+        for i in 0..t.stops.len() {
+            let s = &t.stops[i].info;
+            if let Some(code) = &s.stop_code {
+                t.stops_by_code.entry(code.clone()).or_default().push(i);
+            }
+            let parent = s
+                .parent_station
+                .as_ref()
+                .and_then(|parent| stop_index.get(&(s.feed_id, parent.clone())));
+            if let Some(&p) = parent {
+                t.stops[p].children.push(i);
+            }
+        }
+        for l in rows.stop_links {
+            let a = stop_index.get(&(l.feed_id, l.stop_id));
+            let b = stop_index.get(&(l.linked_feed_id, l.linked_stop_id));
+            if let (Some(&a), Some(&b)) = (a, b) {
+                t.stops[a].links.push(b);
+            }
+        }
+
+        let mut agency_index = HashMap::new();
+        for a in rows.agencies {
+            let timezone = match a.agency_timezone.parse::<Tz>() {
+                Ok(tz) => tz,
+                Err(e) => {
+                    warn!("Agency {} has an invalid timezone: {}", a.agency_id, e);
+                    continue;
+                }
+            };
+            let i = t.agencies.len();
+            t.agencies_by_feed.entry(a.feed_id).or_default().push(i);
+            agency_index.insert((a.feed_id, a.agency_id), i);
+            t.agencies.push(Agency {
+                name: a.agency_name,
+                timezone,
+            });
+        }
+
+        let mut route_index = HashMap::new();
+        for r in rows.routes {
+            if let Some(&agency) = agency_index.get(&(r.feed_id, r.agency_id)) {
+                route_index.insert((r.feed_id, r.route_id), t.routes.len());
+                t.routes.push(Route {
+                    agency,
+                    short_name: r.route_short_name,
+                    long_name: r.route_long_name.unwrap_or_default(),
+                    route_type: r.route_type,
+                });
+            }
+        }
+
+        let mut service_index = HashMap::new();
+        let mut service = |feed_id: i32, service_id: String, t: &mut Timetable| {
+            *service_index
+                .entry((feed_id, service_id))
+                .or_insert_with(|| {
+                    t.service_dates.push(HashSet::new());
+                    t.service_dates.len() - 1
+                })
+        };
+        for d in rows.service_dates {
+            let i = service(d.feed_id, d.service_id, &mut t);
+            t.service_dates[i].insert(d.date);
+        }
+
+        let mut trip_index = HashMap::new();
+        for trip in rows.trips {
+            if let Some(&route) = route_index.get(&(trip.feed_id, trip.route_id)) {
+                trip_index.insert((trip.feed_id, trip.trip_id.clone()), t.trips.len());
+                let service = service(trip.feed_id, trip.service_id, &mut t);
+                t.trips.push(Trip {
+                    trip_id: trip.trip_id,
+                    route,
+                    service,
+                    direction_id: trip.direction_id,
+                    headsign: trip.trip_headsign,
+                    wheelchair_accessible: trip.wheelchair_accessible,
+                    bikes_allowed: trip.bikes_allowed,
+                });
+            }
+        }
+
+        for st in rows.stop_times {
+            if st.pickup_type == Some(1) {
+                continue;
+            }
+            let stop = stop_index.get(&(st.feed_id, st.stop_id));
+            let trip = trip_index.get(&(st.feed_id, st.trip_id));
+            if let (Some(&stop), Some(&trip)) = (stop, trip) {
+                t.stops[stop].departures.push(Departure {
+                    departure_time: st.departure_time,
+                    stop_sequence: st.stop_sequence,
+                    trip,
+                });
+            }
+        }
+        for stop in &mut t.stops {
+            stop.departures.sort_by_key(|d| d.departure_time);
+        }
+        t
+    }
+
+    /// Loads all feeds from the database.
+    pub fn load(connection: &DbConnection) -> QueryResult<Self> {
+        use schema::*;
+
+        let rows = TimetableRows {
+            stops: stop::table.select(model::STOP_COLUMNS).load(connection)?,
+            stop_links: stop_link::table
+                .select((
+                    stop_link::feed_id,
+                    stop_link::stop_id,
+                    stop_link::linked_feed_id,
+                    stop_link::linked_stop_id,
+                ))
+                .load(connection)?,
+            agencies: agency::table
+                .select((
+                    agency::feed_id,
+                    agency::agency_id,
+                    agency::agency_name,
+                    agency::agency_timezone,
+                ))
+                .load(connection)?,
+            routes: route::table
+                .select((
+                    route::feed_id,
+                    route::route_id,
+                    route::agency_id,
+                    route::route_short_name,
+                    route::route_long_name,
+                    route::route_type,
+                ))
+                .load(connection)?,
+            trips: trip::table
+                .select((
+                    trip::feed_id,
+                    trip::trip_id,
+                    trip::route_id,
+                    trip::service_id,
+                    trip::direction_id,
+                    trip::trip_headsign,
+                    trip::wheelchair_accessible,
+                    trip::bikes_allowed,
+                ))
+                .load(connection)?,
+            stop_times: stop_time::table
+                .select((
+                    stop_time::feed_id,
+                    stop_time::trip_id,
+                    stop_time::stop_id,
+                    stop_time::stop_sequence,
+                    stop_time::departure_time,
+                    stop_time::pickup_type,
+                ))
+                .load(connection)?,
+            service_dates: service_date::table
+                .select((
+                    service_date::feed_id,
+                    service_date::service_id,
+                    service_date::date,
+                ))
+                .load(connection)?,
+        };
+        Ok(Self::new(rows))
+    }
+
+    /// The stops with a code.
+    pub fn stops(&self, stop_code: &str) -> Vec<model::Stop> {
+        self.stops_by_code
+            .get(stop_code)
+            .into_iter()
+            .flatten()
+            .map(|&s| self.stops[s].info.clone())
+            .collect()
+    }
+
+    /// Departures from the stops with a code between `from` and `to`, the
+    /// same as `sql_queries/stop_times.sql` with the same parameters.
+    pub fn departures(
+        &self,
+        stop_code: &str,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Vec<StopTimeByStop> {
+        let mut found = Vec::new();
+        self.scan_departures(stop_code, from, to, |d| {
+            found.push(d);
+            true
+        });
+        found.sort_by_key(|d| d.departure_time);
+        found.iter().map(|d| self.stop_time(d)).collect()
+    }
+
+    /// The first of [`Timetable::departures`], without building the others.
+    pub fn first_departure(
+        &self,
+        stop_code: &str,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+    ) -> Option<StopTimeByStop> {
+        let mut first: Option<FoundDeparture> = None;
+        self.scan_departures(stop_code, from, to, |d| {
+            match &first {
+                Some(f) if f.departure_time <= d.departure_time => {}
+                _ => first = Some(d),
+            }
+            // the rest of the day's departures from the stop are later
+            false
+        });
+        first.map(|d| self.stop_time(&d))
+    }
+
+    /// Calls `f` with the departures from the stops with a code between
+    /// `from` and `to`, in order of time for each stop, agency and service
+    /// date. If `f` returns false, the later ones for that stop, agency and
+    /// service date are skipped.
+    fn scan_departures(
+        &self,
+        stop_code: &str,
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        mut f: impl FnMut(FoundDeparture) -> bool,
+    ) {
+        // stops with the code, stops in other feeds linked to them, and the
+        // platforms of a station with the code
+        let mut matched = Vec::new();
+        for &s in self.stops_by_code.get(stop_code).into_iter().flatten() {
+            matched.push(s);
+            matched.extend(&self.stops[s].links);
+        }
+        let mut board = matched.clone();
+        for &s in &matched {
+            board.extend(&self.stops[s].children);
+        }
+        board.sort_unstable();
+        board.dedup();
+
+        for s in board {
+            let stop = &self.stops[s];
+            for &a in self
+                .agencies_by_feed
+                .get(&stop.info.feed_id)
+                .into_iter()
+                .flatten()
+            {
+                let agency = &self.agencies[a];
+                let mut midnight = local_midnight(from - Duration::hours(24), agency.timezone);
+                while midnight <= to {
+                    let service_date = midnight
+                        .with_timezone(&agency.timezone)
+                        .naive_local()
+                        .date();
+                    let min = ceil_seconds(from - midnight);
+                    let max = floor_seconds(to - midnight);
+                    let start = stop
+                        .departures
+                        .partition_point(|d| i64::from(d.departure_time) < min);
+                    for (i, d) in stop.departures[start..]
+                        .iter()
+                        .enumerate()
+                        .take_while(|(_, d)| i64::from(d.departure_time) <= max)
+                    {
+                        let trip = &self.trips[d.trip];
+                        if self.routes[trip.route].agency != a
+                            || !self.service_dates[trip.service].contains(&service_date)
+                        {
+                            continue;
+                        }
+                        let found = FoundDeparture {
+                            stop: s,
+                            departure: start + i,
+                            agency: a,
+                            departure_time: midnight + Duration::seconds(d.departure_time.into()),
+                            service_date,
+                        };
+                        if !f(found) {
+                            break;
+                        }
+                    }
+                    // like generate_series with '1 day' in a UTC session
+                    midnight += Duration::hours(24);
+                }
+            }
+        }
+    }
+
+    fn stop_time(&self, found: &FoundDeparture) -> StopTimeByStop {
+        let stop = &self.stops[found.stop];
+        let d = &stop.departures[found.departure];
+        let trip = &self.trips[d.trip];
+        let route = &self.routes[trip.route];
+        StopTimeByStop {
+            stop_id: stop.info.stop_id.clone(),
+            trip_id: trip.trip_id.clone(),
+            departure_time: found.departure_time,
+            service_date: found.service_date,
+            stop_sequence: d.stop_sequence,
+            direction_id: trip.direction_id,
+            trip_headsign: trip.headsign.clone(),
+            route_short_name: route.short_name.clone(),
+            route_long_name: route.long_name.clone(),
+            route_type: route.route_type,
+            wheelchair_accessible: trip.wheelchair_accessible,
+            bikes_allowed: trip.bikes_allowed,
+            wheelchair_boarding: stop.info.wheelchair_boarding,
+            feed_id: stop.info.feed_id,
+            agency_name: self.agencies[found.agency].name.clone(),
+        }
+    }
+}
+
+/// 00:00 in `timezone` on the day of `t` there.
+fn local_midnight(t: DateTime<Utc>, timezone: Tz) -> DateTime<Utc> {
+    let date = t.with_timezone(&timezone).naive_local().date();
+    // midnight doesn't exist on days when clocks go forward at midnight
+    (0..24)
+        .filter_map(|h| {
+            timezone
+                .from_local_datetime(&date.and_hms_opt(h, 0, 0)?)
+                .earliest()
+        })
+        .next()
+        .map(|t| t.with_timezone(&Utc))
+        .unwrap_or(t)
+}
+
+fn ceil_seconds(d: Duration) -> i64 {
+    let micros = d.num_microseconds().unwrap_or(i64::MAX);
+    micros.div_euclid(1_000_000) + i64::from(micros.rem_euclid(1_000_000) != 0)
+}
+
+fn floor_seconds(d: Duration) -> i64 {
+    d.num_microseconds()
+        .unwrap_or(i64::MAX)
+        .div_euclid(1_000_000)
+}
+
+/// Identifies the imported feeds and stop links, to know when to reload.
+#[derive(QueryableByName, PartialEq)]
+struct Version {
+    #[sql_type = "Text"]
+    version: String,
+}
+
+fn version(connection: &DbConnection) -> QueryResult<Version> {
+    diesel::sql_query(
+        "select (select coalesce(string_agg(feed_id || ' ' || imported_at, ',' order by feed_id), '') from feed)
+            || ' ' || (select md5(coalesce(string_agg(feed_id || ' ' || stop_id || ' ' || linked_feed_id || ' ' || linked_stop_id, ','
+                order by feed_id, stop_id, linked_feed_id, linked_stop_id), '')) from stop_link)
+            as version",
+    )
+    .get_result(connection)
+}
+
+/// Loads the timetable, returning it with the version of the data it has.
+async fn load(pool: &ConnectionPool) -> Result<(Version, Timetable), String> {
+    let pool = pool.clone();
+    tokio::task::spawn_blocking(move || {
+        let connection = pool.get().map_err(|e| e.to_string())?;
+        let started = std::time::Instant::now();
+        let version = version(&connection).map_err(|e| e.to_string())?;
+        let timetable = Timetable::load(&connection).map_err(|e| e.to_string())?;
+        info!(
+            "Loaded timetable of {} stops and {} trips in {:?}",
+            timetable.stops.len(),
+            timetable.trips.len(),
+            started.elapsed()
+        );
+        Ok((version, timetable))
+    })
+    .await
+    .map_err(|e| e.to_string())?
+}
+
+/// Loads the timetable, panicking if it can't be loaded.
+pub async fn load_shared(
+    pool: &ConnectionPool,
+) -> (SharedTimetable, impl std::future::Future<Output = ()>) {
+    let (version, timetable) = load(pool)
+        .await
+        .unwrap_or_else(|e| panic!("Could not load the timetable: {}", e));
+    let shared = Arc::new(RwLock::new(timetable));
+    let reload = keep_loaded(pool.clone(), shared.clone(), version);
+    (shared, reload)
+}
+
+/// Reloads the timetable whenever feeds are imported or deleted, or stops
+/// are linked.
+async fn keep_loaded(pool: ConnectionPool, timetable: SharedTimetable, mut loaded: Version) {
+    let mut interval = tokio::time::interval(RELOAD_CHECK_INTERVAL);
+    interval.tick().await; // 0 second tick
+    loop {
+        interval.tick().await;
+        let p = pool.clone();
+        let current = tokio::task::spawn_blocking(move || {
+            let connection = p.get().map_err(|e| e.to_string())?;
+            version(&connection).map_err(|e| e.to_string())
+        })
+        .await
+        .map_err(|e| e.to_string())
+        .and_then(|v| v);
+        match current {
+            Ok(v) if v == loaded => continue,
+            Ok(_) => (),
+            Err(e) => {
+                error!("Error checking for feed changes: {}", e);
+                continue;
+            }
+        }
+        info!("Feeds have changed, reloading timetable");
+        match load(&pool).await {
+            Ok((version, t)) => {
+                *timetable.write().unwrap() = t;
+                loaded = version;
+            }
+            Err(e) => error!("Error reloading timetable: {}", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rows() -> TimetableRows {
+        let stop =
+            |feed_id, stop_id: &str, stop_code: Option<&str>, parent: Option<&str>| model::Stop {
+                feed_id,
+                stop_id: stop_id.into(),
+                stop_code: stop_code.map(Into::into),
+                stop_name: format!("Stop {}", stop_id),
+                stop_lat: -36.8,
+                stop_lon: 174.7,
+                parent_station: parent.map(Into::into),
+                location_type: None,
+                wheelchair_boarding: None,
+                platform_code: None,
+            };
+        let trip = |feed_id, trip_id: &str, service_id: &str| TripRow {
+            feed_id,
+            trip_id: trip_id.into(),
+            route_id: "R".into(),
+            service_id: service_id.into(),
+            direction_id: None,
+            trip_headsign: None,
+            wheelchair_accessible: None,
+            bikes_allowed: None,
+        };
+        let stop_time =
+            |feed_id, trip_id: &str, stop_id: &str, departure_time, pickup_type| StopTimeRow {
+                feed_id,
+                trip_id: trip_id.into(),
+                stop_id: stop_id.into(),
+                stop_sequence: 1,
+                departure_time,
+                pickup_type,
+            };
+        let date = |feed_id, service_id: &str, d| ServiceDateRow {
+            feed_id,
+            service_id: service_id.into(),
+            date: NaiveDate::from_ymd_opt(2020, 1, d).unwrap(),
+        };
+        TimetableRows {
+            stops: vec![
+                stop(1, "P", Some("100"), None),
+                stop(1, "A", None, Some("P")),
+                stop(2, "X", Some("900"), None),
+            ],
+            stop_links: vec![StopLinkRow {
+                feed_id: 1,
+                stop_id: "P".into(),
+                linked_feed_id: 2,
+                linked_stop_id: "X".into(),
+            }],
+            agencies: vec![
+                AgencyRow {
+                    feed_id: 1,
+                    agency_id: "AT".into(),
+                    agency_name: "Auckland Transport".into(),
+                    agency_timezone: "Pacific/Auckland".into(),
+                },
+                AgencyRow {
+                    feed_id: 2,
+                    agency_id: "AT".into(),
+                    agency_name: "Other".into(),
+                    agency_timezone: "Pacific/Auckland".into(),
+                },
+            ],
+            routes: vec![1, 2]
+                .into_iter()
+                .map(|feed_id| RouteRow {
+                    feed_id,
+                    route_id: "R".into(),
+                    agency_id: "AT".into(),
+                    route_short_name: None,
+                    route_long_name: None,
+                    route_type: 3,
+                })
+                .collect(),
+            trips: vec![
+                trip(1, "T1", "WK"),
+                trip(1, "T2", "WK"),
+                trip(2, "T3", "WK"),
+                trip(2, "T4", "ALL"),
+            ],
+            stop_times: vec![
+                stop_time(1, "T1", "A", 8 * 3600, None),
+                // after midnight, on the previous service day
+                stop_time(1, "T2", "A", 25 * 3600, None),
+                // no pickup
+                stop_time(1, "T2", "P", 9 * 3600, Some(1)),
+                stop_time(2, "T3", "X", 8 * 3600 + 60, None),
+                // runs on a service without dates
+                stop_time(2, "T4", "X", 8 * 3600 + 120, None),
+            ],
+            service_dates: vec![date(1, "WK", 6), date(1, "WK", 7), date(2, "WK", 7)],
+        }
+    }
+
+    #[test]
+    fn departures() {
+        let timetable = Timetable::new(rows());
+        let t = |s| DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc);
+        let departures = |code, from, to| {
+            timetable
+                .departures(code, t(from), t(to))
+                .into_iter()
+                .map(|d| (d.trip_id, d.stop_id, d.departure_time, d.service_date.day()))
+                .collect::<Vec<_>>()
+        };
+
+        // T3 is at a stop of another feed linked to P
+        assert_eq!(
+            departures(
+                "100",
+                "2020-01-07T07:00:00+13:00",
+                "2020-01-08T02:00:00+13:00"
+            ),
+            vec![
+                ("T1".into(), "A".into(), t("2020-01-07T08:00:00+13:00"), 7),
+                ("T3".into(), "X".into(), t("2020-01-07T08:01:00+13:00"), 7),
+                ("T2".into(), "A".into(), t("2020-01-08T01:00:00+13:00"), 7),
+            ]
+        );
+        assert_eq!(
+            timetable
+                .first_departure(
+                    "100",
+                    t("2020-01-07T07:00:00+13:00"),
+                    t("2020-01-08T02:00:00+13:00")
+                )
+                .map(|d| (d.trip_id, d.departure_time)),
+            Some(("T1".into(), t("2020-01-07T08:00:00+13:00")))
+        );
+        // the service doesn't run on the 8th
+        assert_eq!(
+            departures(
+                "100",
+                "2020-01-08T02:00:00+13:00",
+                "2020-01-08T12:00:00+13:00"
+            ),
+            vec![]
+        );
+        assert!(timetable
+            .first_departure(
+                "100",
+                t("2020-01-08T02:00:00+13:00"),
+                t("2020-01-08T12:00:00+13:00")
+            )
+            .is_none());
+        // only the link from P to X is in stop_links
+        assert_eq!(
+            departures(
+                "900",
+                "2020-01-07T07:00:00+13:00",
+                "2020-01-07T09:00:00+13:00"
+            ),
+            vec![("T3".into(), "X".into(), t("2020-01-07T08:01:00+13:00"), 7)]
+        );
+        assert_eq!(
+            departures(
+                "101",
+                "2020-01-07T07:00:00+13:00",
+                "2020-01-07T09:00:00+13:00"
+            ),
+            vec![]
+        );
+
+        // link_stops adds links in both directions
+        let mut rows = rows();
+        rows.stop_links.push(StopLinkRow {
+            feed_id: 2,
+            stop_id: "X".into(),
+            linked_feed_id: 1,
+            linked_stop_id: "P".into(),
+        });
+        let timetable = Timetable::new(rows);
+        assert_eq!(
+            timetable
+                .departures(
+                    "900",
+                    t("2020-01-07T07:00:00+13:00"),
+                    t("2020-01-07T09:00:00+13:00")
+                )
+                .into_iter()
+                .map(|d| (d.trip_id, d.stop_id, d.departure_time))
+                .collect::<Vec<_>>(),
+            vec![
+                ("T1".into(), "A".into(), t("2020-01-07T08:00:00+13:00")),
+                ("T3".into(), "X".into(), t("2020-01-07T08:01:00+13:00")),
+            ]
+        );
+    }
+
+    #[test]
+    fn stops() {
+        let timetable = Timetable::new(rows());
+        let stops = timetable.stops("100");
+        assert_eq!(stops.len(), 1);
+        assert_eq!(
+            (stops[0].feed_id, stops[0].stop_name.as_str()),
+            (1, "Stop P")
+        );
+        assert!(timetable.stops("101").is_empty());
+    }
+
+    /// Compares the time taken to find departures in memory and with the
+    /// query, for every stop code in the database at DATABASE_URL, checking
+    /// that both give the same departures. Run with
+    /// `cargo test --release -- --ignored --nocapture compare_with_query`.
+    #[test]
+    #[ignore]
+    fn compare_with_query() {
+        use diesel::pg::types::sql_types::Timestamptz;
+
+        dotenv::dotenv().ok();
+        let pool = crate::database::create_connection_pool(&crate::config::DatabaseConfig {
+            url: std::env::var("DATABASE_URL").ok(),
+            ..Default::default()
+        });
+        let connection = pool.get().unwrap();
+        let timetable = Timetable::load(&connection).unwrap();
+
+        let mut codes = timetable.stops_by_code.keys().cloned().collect::<Vec<_>>();
+        codes.sort();
+        codes.truncate(200);
+        // a day when the feed is in service
+        let from: Option<NaiveDate> = schema::service_date::table
+            .select(diesel::dsl::min(schema::service_date::date))
+            .first(&connection)
+            .unwrap();
+        let from = from.expect("no feeds in service");
+        let from = Utc.from_utc_datetime(&from.and_hms_opt(6, 0, 0).unwrap());
+        let to = from + Duration::hours(12);
+
+        let sort = |mut d: Vec<StopTimeByStop>| {
+            d.sort_by(|a, b| {
+                (a.departure_time, a.feed_id, &a.trip_id, &a.stop_id).cmp(&(
+                    b.departure_time,
+                    b.feed_id,
+                    &b.trip_id,
+                    &b.stop_id,
+                ))
+            });
+            d
+        };
+        let (mut memory_time, mut query_time, mut count) = (Duration::zero(), Duration::zero(), 0);
+        for code in &codes {
+            let started = Utc::now();
+            let memory = timetable.departures(code, from, to);
+            memory_time += Utc::now() - started;
+
+            let started = Utc::now();
+            let query: Vec<StopTimeByStop> =
+                diesel::sql_query(include_str!("sql_queries/stop_times.sql"))
+                    .bind::<Timestamptz, _>(from)
+                    .bind::<Timestamptz, _>(to)
+                    .bind::<Text, _>(code)
+                    .load(&connection)
+                    .unwrap();
+            query_time += Utc::now() - started;
+
+            count += memory.len();
+            assert_eq!(
+                timetable
+                    .first_departure(code, from, to)
+                    .map(|d| d.departure_time),
+                query.first().map(|d| d.departure_time),
+                "stop code {}",
+                code
+            );
+            assert_eq!(sort(memory), sort(query), "stop code {}", code);
+        }
+        println!(
+            "{} stop codes, {} departures: {}µs per request in memory, {}µs per query",
+            codes.len(),
+            count,
+            memory_time.num_microseconds().unwrap() / codes.len() as i64,
+            query_time.num_microseconds().unwrap() / codes.len() as i64,
+        );
+    }
+}
@@ -0,0 +1,396 @@
+//! Vehicle positions from the realtime feed, streamed to websocket clients.
+//!
+//! A client sends a subscription, `{"bbox": [min_lon, min_lat, max_lon, max_lat]}`
+//! and/or `{"routes": ["route_id", ...]}`, and is sent a `snapshot` of the
+//! vehicles it matches, then a `delta` of the vehicles which moved, appeared
+//! or left it each time a feed is fetched. Sending another subscription
+//! replaces it. A client too slow to keep up with the deltas is sent a new
+//! snapshot once it catches up.
+
+use futures::{SinkExt, StreamExt};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+use warp::ws::{Message, WebSocket};
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use crate::protobuf::gtfs_realtime::FeedMessage;
+
+/// Deltas kept for clients which haven't received them yet.
+const DELTA_CAPACITY: usize = 8;
+
+#[derive(Serialize, Clone, PartialEq, Debug)]
+pub struct Vehicle {
+    /// the vehicle's id, or the feed entity's if it has none
+    pub id: String,
+    pub label: Option<String>,
+    pub trip_id: Option<String>,
+    pub route_id: Option<String>,
+    pub latitude: f32,
+    pub longitude: f32,
+    pub bearing: Option<f32>,
+    /// in metres per second
+    pub speed: Option<f32>,
+    /// POSIX time of the position
+    pub timestamp: Option<u64>,
+}
+
+/// The changes in vehicles from one feed to the next.
+#[derive(Debug)]
+pub struct VehicleDelta {
+    seq: u64,
+    updated: Vec<Vehicle>,
+    removed: Vec<String>,
+}
+
+pub struct Vehicles {
+    vehicles: HashMap<String, Vehicle>,
+    /// incremented for each delta
+    seq: u64,
+    deltas: broadcast::Sender<Arc<VehicleDelta>>,
+}
+
+impl Vehicles {
+    pub fn new() -> Self {
+        Self {
+            vehicles: HashMap::new(),
+            seq: 0,
+            deltas: broadcast::channel(DELTA_CAPACITY).0,
+        }
+    }
+
+    pub fn load_feed(&mut self, feed: &FeedMessage) {
+        let mut vehicles = HashMap::new();
+        for entity in &feed.entity {
+            let vp = match &entity.vehicle {
+                Some(vp) => vp,
+                None => continue,
+            };
+            let position = match &vp.position {
+                Some(p) => p,
+                None => {
+                    warn!("No position found for a VehiclePosition");
+                    continue;
+                }
+            };
+            let descriptor = vp.vehicle.as_ref();
+            let id = descriptor
+                .and_then(|d| d.id.clone())
+                .unwrap_or_else(|| entity.id.clone());
+            vehicles.insert(
+                id.clone(),
+                Vehicle {
+                    id,
+                    label: descriptor.and_then(|d| d.label.clone()),
+                    trip_id: vp.trip.as_ref().and_then(|t| t.trip_id.clone()),
+                    route_id: vp.trip.as_ref().and_then(|t| t.route_id.clone()),
+                    latitude: position.latitude,
+                    longitude: position.longitude,
+                    bearing: position.bearing,
+                    speed: position.speed,
+                    timestamp: vp.timestamp,
+                },
+            );
+        }
+
+        let updated = vehicles
+            .values()
+            .filter(|v| self.vehicles.get(&v.id) != Some(v))
+            .cloned()
+            .collect::<Vec<_>>();
+        let removed = self
+            .vehicles
+            .keys()
+            .filter(|id| !vehicles.contains_key(*id))
+            .cloned()
+            .collect::<Vec<_>>();
+        self.vehicles = vehicles;
+        if updated.is_empty() && removed.is_empty() {
+            return;
+        }
+        self.seq += 1;
+        // there may be no one listening
+        let _ = self.deltas.send(Arc::new(VehicleDelta {
+            seq: self.seq,
+            updated,
+            removed,
+        }));
+    }
+
+    /// The vehicles matching a subscription, and the seq of the last delta
+    /// they include.
+    fn snapshot(&self, subscription: &Subscription) -> (Vec<Vehicle>, u64) {
+        let vehicles = self
+            .vehicles
+            .values()
+            .filter(|v| subscription.matches(v))
+            .cloned()
+            .collect();
+        (vehicles, self.seq)
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+struct Subscription {
+    /// min_lon, min_lat, max_lon, max_lat
+    bbox: Option<[f32; 4]>,
+    routes: Option<HashSet<String>>,
+}
+
+impl Subscription {
+    fn matches(&self, v: &Vehicle) -> bool {
+        let in_bbox = match self.bbox {
+            None => true,
+            Some([min_lon, min_lat, max_lon, max_lat]) => {
+                (min_lon..=max_lon).contains(&v.longitude)
+                    && (min_lat..=max_lat).contains(&v.latitude)
+            }
+        };
+        let on_route = match (&self.routes, &v.route_id) {
+            (None, _) => true,
+            (Some(routes), Some(route_id)) => routes.contains(route_id),
+            (Some(_), None) => false,
+        };
+        in_bbox && on_route
+    }
+}
+
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ServerMessage {
+    Snapshot {
+        vehicles: Vec<Vehicle>,
+    },
+    Delta {
+        updated: Vec<Vehicle>,
+        removed: Vec<String>,
+    },
+    Error {
+        message: String,
+    },
+}
+
+/// What a client has been sent.
+struct Client {
+    subscription: Subscription,
+    /// ids of the vehicles it has, which it should be told about if they leave
+    sent: HashSet<String>,
+    /// the seq of the last delta included
+    seq: u64,
+}
+
+impl Client {
+    fn snapshot(&mut self, vehicles: &Vehicles) -> ServerMessage {
+        let (vehicles, seq) = vehicles.snapshot(&self.subscription);
+        self.sent = vehicles.iter().map(|v| v.id.clone()).collect();
+        self.seq = seq;
+        ServerMessage::Snapshot { vehicles }
+    }
+
+    /// The part of a delta for this client, if there is any.
+    fn delta(&mut self, delta: &VehicleDelta) -> Option<ServerMessage> {
+        // already in a snapshot
+        if delta.seq <= self.seq {
+            return None;
+        }
+        self.seq = delta.seq;
+        let mut updated = Vec::new();
+        let mut removed = Vec::new();
+        for v in &delta.updated {
+            if self.subscription.matches(v) {
+                self.sent.insert(v.id.clone());
+                updated.push(v.clone());
+            } else if self.sent.remove(&v.id) {
+                removed.push(v.id.clone());
+            }
+        }
+        for id in &delta.removed {
+            if self.sent.remove(id) {
+                removed.push(id.clone());
+            }
+        }
+        if updated.is_empty() && removed.is_empty() {
+            None
+        } else {
+            Some(ServerMessage::Delta { updated, removed })
+        }
+    }
+}
+
+/// Handles a websocket connection until it is closed.
+pub async fn client_connected(socket: WebSocket, vehicles: Arc<Mutex<Vehicles>>) {
+    let (mut tx, mut rx) = socket.split();
+    let mut deltas = vehicles.lock().unwrap().deltas.subscribe();
+    // nothing is sent until the client subscribes
+    let mut client: Option<Client> = None;
+
+    loop {
+        let reply = tokio::select! {
+            message = rx.next() => match message {
+                Some(Ok(message)) if message.is_text() => {
+                    match serde_json::from_str::<Subscription>(message.to_str().unwrap_or_default()) {
+                        Ok(subscription) => {
+                            let c = client.get_or_insert(Client {
+                                subscription: Subscription::default(),
+                                sent: HashSet::new(),
+                                seq: 0,
+                            });
+                            c.subscription = subscription;
+                            Some(c.snapshot(&vehicles.lock().unwrap()))
+                        }
+                        Err(e) => Some(ServerMessage::Error {
+                            message: format!("Invalid subscription: {}", e),
+                        }),
+                    }
+                }
+                Some(Ok(message)) if message.is_close() => break,
+                // pings are answered by warp
+                Some(Ok(_)) => None,
+                Some(Err(e)) => {
+                    debug!("Websocket error: {}", e);
+                    break;
+                }
+                None => break,
+            },
+            delta = deltas.recv() => match (delta, &mut client) {
+                (Ok(delta), Some(c)) => c.delta(&delta),
+                // the client has been too slow, so it's sent everything again
+                (Err(broadcast::RecvError::Lagged(n)), Some(c)) => {
+                    debug!("Websocket client missed {} deltas", n);
+                    Some(c.snapshot(&vehicles.lock().unwrap()))
+                }
+                (Err(broadcast::RecvError::Closed), _) => break,
+                (_, None) => None,
+            },
+        };
+        if let Some(reply) = reply {
+            let text = serde_json::to_string(&reply).expect("messages are always serializable");
+            if tx.send(Message::text(text)).await.is_err() {
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protobuf::gtfs_realtime::*;
+
+    fn feed(positions: &[(&str, &str, f32, f32)]) -> FeedMessage {
+        FeedMessage {
+            header: FeedHeader {
+                gtfs_realtime_version: "2.0".into(),
+                incrementality: None,
+                timestamp: None,
+            },
+            entity: positions
+                .iter()
+                .map(|&(id, route_id, longitude, latitude)| FeedEntity {
+                    id: format!("e{}", id),
+                    is_deleted: None,
+                    trip_update: None,
+                    alert: None,
+                    vehicle: Some(VehiclePosition {
+                        trip: Some(TripDescriptor {
+                            route_id: Some(route_id.into()),
+                            ..Default::default()
+                        }),
+                        vehicle: Some(VehicleDescriptor {
+                            id: Some(id.into()),
+                            ..Default::default()
+                        }),
+                        position: Some(Position {
+                            latitude,
+                            longitude,
+                            ..Default::default()
+                        }),
+                        ..Default::default()
+                    }),
+                })
+                .collect(),
+        }
+    }
+
+    fn ids(message: Option<ServerMessage>) -> (Vec<String>, Vec<String>) {
+        match message {
+            Some(ServerMessage::Snapshot { vehicles }) => {
+                let mut v = vehicles.into_iter().map(|v| v.id).collect::<Vec<_>>();
+                v.sort();
+                (v, vec![])
+            }
+            Some(ServerMessage::Delta { updated, removed }) => {
+                (updated.into_iter().map(|v| v.id).collect(), removed)
+            }
+            _ => (vec![], vec![]),
+        }
+    }
+
+    #[test]
+    fn deltas() {
+        let mut vehicles = Vehicles::new();
+        let mut deltas = vehicles.deltas.subscribe();
+        vehicles.load_feed(&feed(&[
+            ("a", "R1", 174.7, -36.8),
+            ("b", "R2", 174.7, -36.8),
+        ]));
+
+        let mut client = Client {
+            subscription: Subscription {
+                bbox: Some([174.6, -36.9, 174.8, -36.7]),
+                routes: None,
+            },
+            sent: HashSet::new(),
+            seq: 0,
+        };
+        let s = |v: &[&str]| v.iter().map(|&v| v.to_owned()).collect::<Vec<_>>();
+        assert_eq!(
+            ids(Some(client.snapshot(&vehicles))),
+            (s(&["a", "b"]), s(&[]))
+        );
+        // the first delta is already in the snapshot
+        let delta = deltas.try_recv().unwrap();
+        assert_eq!(client.delta(&delta), None);
+
+        // a leaves the box, b disappears, and c appears
+        vehicles.load_feed(&feed(&[
+            ("a", "R1", 175.0, -36.8),
+            ("c", "R1", 174.7, -36.8),
+        ]));
+        let delta = deltas.try_recv().unwrap();
+        assert_eq!(ids(client.delta(&delta)), (s(&["c"]), s(&["a", "b"])));
+
+        // nothing changes
+        vehicles.load_feed(&feed(&[
+            ("a", "R1", 175.0, -36.8),
+            ("c", "R1", 174.7, -36.8),
+        ]));
+        assert!(deltas.try_recv().is_err());
+
+        // a moves, but is still outside the box
+        vehicles.load_feed(&feed(&[
+            ("a", "R1", 175.1, -36.8),
+            ("c", "R1", 174.7, -36.8),
+        ]));
+        let delta = deltas.try_recv().unwrap();
+        assert_eq!(client.delta(&delta), None);
+
+        client.subscription = Subscription {
+            bbox: None,
+            routes: Some(vec!["R2".to_owned()].into_iter().collect()),
+        };
+        assert_eq!(ids(Some(client.snapshot(&vehicles))), (s(&[]), s(&[])));
+    }
+
+    #[test]
+    fn subscription() {
+        let parse = |s| serde_json::from_str::<Subscription>(s);
+        assert!(parse(r#"{"bbox": [174.6, -36.9, 174.8, -36.7]}"#).is_ok());
+        assert!(parse(r#"{"routes": ["R1"]}"#).is_ok());
+        assert!(parse(r#"{"bbox": [174.6, -36.9]}"#).is_err());
+        assert!(parse(r#"{"box": [174.6, -36.9, 174.8, -36.7]}"#).is_err());
+    }
+}
@@ -0,0 +1,52 @@
+use tokio_postgres::Transaction;
+
+use crate::ImporterError;
+
+/// Names of the columns of `table` which can be filled from a GTFS file,
+/// i.e. all except feed_id.
+pub async fn table_columns(
+    transaction: &Transaction<'_>,
+    table: &str,
+) -> Result<Vec<String>, ImporterError> {
+    let rows = transaction
+        .query(
+            "select column_name::text from information_schema.columns \
+         where table_schema = current_schema() and table_name = $1 and column_name != 'feed_id'",
+            &[&table],
+        )
+        .await?;
+    Ok(rows.iter().map(|r| r.get(0)).collect())
+}
+
+/// Names and data types of the columns of `table` except feed_id, in the
+/// order they were defined.
+pub async fn table_column_types(
+    transaction: &Transaction<'_>,
+    table: &str,
+) -> Result<Vec<(String, String)>, ImporterError> {
+    let rows = transaction
+        .query(
+            "select column_name::text, data_type::text from information_schema.columns \
+         where table_schema = current_schema() and table_name = $1 and column_name != 'feed_id' \
+         order by ordinal_position",
+            &[&table],
+        )
+        .await?;
+    Ok(rows.iter().map(|r| (r.get(0), r.get(1))).collect())
+}
+
+/// Rewrites csv data (including the header), keeping only the columns at
+/// `indices`, in that order.
+pub fn select_columns(content: &[u8], indices: &[usize]) -> Result<Vec<u8>, ImporterError> {
+    let mut reader = csv::ReaderBuilder::new()
+        .has_headers(false)
+        .from_reader(content);
+    let mut writer = csv::Writer::from_writer(Vec::new());
+    for record in reader.records() {
+        let record = record?;
+        writer.write_record(indices.iter().map(|&i| record.get(i).unwrap_or("")))?;
+    }
+    writer
+        .into_inner()
+        .map_err(|e| ImporterError::FileError(e.into_error()))
+}
@@ -0,0 +1,381 @@
+use serde::Serialize;
+use tokio_postgres::Client;
+
+use std::collections::BTreeMap;
+
+use crate::stop_times::haversine_distance;
+use crate::ImporterError;
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct StopInfo {
+    pub stop_id: String,
+    pub stop_name: String,
+    #[serde(skip)]
+    pub position: (f64, f64),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct RouteInfo {
+    pub route_id: String,
+    pub route_short_name: Option<String>,
+    pub route_long_name: Option<String>,
+}
+
+/// Number of trips of a route on each type of day, counted from calendar.
+/// A trip counts as a weekday trip if it runs on any day from Monday to
+/// Friday. Services only defined in calendar_date are not counted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct TripCounts {
+    pub weekday: i64,
+    pub saturday: i64,
+    pub sunday: i64,
+}
+
+/// What is compared of a feed, keyed by id.
+#[derive(Debug, Default)]
+pub struct FeedContent {
+    pub feed_id: i32,
+    pub stops: BTreeMap<String, StopInfo>,
+    pub routes: BTreeMap<String, RouteInfo>,
+    pub trip_counts: BTreeMap<String, TripCounts>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct Renamed<T> {
+    pub from: T,
+    pub to: T,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct MovedStop {
+    pub stop_id: String,
+    pub stop_name: String,
+    /// in metres
+    pub distance: f64,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct TripCountChange {
+    pub route_id: String,
+    pub from: TripCounts,
+    pub to: TripCounts,
+}
+
+#[derive(Debug, Default, PartialEq, Serialize)]
+pub struct FeedDiff {
+    pub from_feed_id: i32,
+    pub to_feed_id: i32,
+    pub added_stops: Vec<StopInfo>,
+    pub removed_stops: Vec<StopInfo>,
+    pub renamed_stops: Vec<Renamed<StopInfo>>,
+    pub moved_stops: Vec<MovedStop>,
+    pub added_routes: Vec<RouteInfo>,
+    pub removed_routes: Vec<RouteInfo>,
+    pub renamed_routes: Vec<Renamed<RouteInfo>>,
+    pub trip_count_changes: Vec<TripCountChange>,
+}
+
+pub async fn load_feed(client: &Client, feed_id: i32) -> Result<FeedContent, ImporterError> {
+    if client
+        .query_opt("select 1 from feed where feed_id = $1", &[&feed_id])
+        .await?
+        .is_none()
+    {
+        return Err(ImporterError::FeedNotFound(feed_id));
+    }
+
+    let mut content = FeedContent {
+        feed_id,
+        ..Default::default()
+    };
+    for r in client
+        .query(
+            "select stop_id, stop_name, stop_lat, stop_lon from stop where feed_id = $1",
+            &[&feed_id],
+        )
+        .await?
+    {
+        let stop = StopInfo {
+            stop_id: r.get(0),
+            stop_name: r.get(1),
+            position: (r.get(2), r.get(3)),
+        };
+        content.stops.insert(stop.stop_id.clone(), stop);
+    }
+    for r in client
+        .query(
+            "select route_id, route_short_name, route_long_name from route where feed_id = $1",
+            &[&feed_id],
+        )
+        .await?
+    {
+        let route = RouteInfo {
+            route_id: r.get(0),
+            route_short_name: r.get(1),
+            route_long_name: r.get(2),
+        };
+        content.routes.insert(route.route_id.clone(), route);
+    }
+    for r in client
+        .query(
+            "select t.route_id,
+            count(*) filter (where c.monday or c.tuesday or c.wednesday or c.thursday or c.friday),
+            count(*) filter (where c.saturday),
+            count(*) filter (where c.sunday)
+        from trip t join calendar c on c.feed_id = t.feed_id and c.service_id = t.service_id
+        where t.feed_id = $1
+        group by t.route_id",
+            &[&feed_id],
+        )
+        .await?
+    {
+        content.trip_counts.insert(
+            r.get(0),
+            TripCounts {
+                weekday: r.get(1),
+                saturday: r.get(2),
+                sunday: r.get(3),
+            },
+        );
+    }
+    Ok(content)
+}
+
+/// Compares two versions of a feed by stop_id and route_id. Stops which
+/// moved more than `moved_distance` metres are reported.
+pub fn diff(from: &FeedContent, to: &FeedContent, moved_distance: f64) -> FeedDiff {
+    let mut diff = FeedDiff {
+        from_feed_id: from.feed_id,
+        to_feed_id: to.feed_id,
+        ..Default::default()
+    };
+
+    for (id, stop) in &to.stops {
+        match from.stops.get(id) {
+            None => diff.added_stops.push(stop.clone()),
+            Some(old) => {
+                if old.stop_name != stop.stop_name {
+                    diff.renamed_stops.push(Renamed {
+                        from: old.clone(),
+                        to: stop.clone(),
+                    });
+                }
+                let distance = haversine_distance(old.position, stop.position);
+                if distance > moved_distance {
+                    diff.moved_stops.push(MovedStop {
+                        stop_id: id.clone(),
+                        stop_name: stop.stop_name.clone(),
+                        distance,
+                    });
+                }
+            }
+        }
+    }
+    diff.removed_stops = from
+        .stops
+        .iter()
+        .filter(|(id, _)| !to.stops.contains_key(*id))
+        .map(|(_, s)| s.clone())
+        .collect();
+
+    for (id, route) in &to.routes {
+        match from.routes.get(id) {
+            None => diff.added_routes.push(route.clone()),
+            Some(old) if old != route => diff.renamed_routes.push(Renamed {
+                from: old.clone(),
+                to: route.clone(),
+            }),
+            Some(_) => {}
+        }
+    }
+    diff.removed_routes = from
+        .routes
+        .iter()
+        .filter(|(id, _)| !to.routes.contains_key(*id))
+        .map(|(_, r)| r.clone())
+        .collect();
+
+    let mut route_ids = from.trip_counts.keys().collect::<Vec<_>>();
+    route_ids.extend(to.trip_counts.keys());
+    route_ids.sort_unstable();
+    route_ids.dedup();
+    for id in route_ids {
+        let counts = |c: &FeedContent| c.trip_counts.get(id).copied().unwrap_or_default();
+        let (old, new) = (counts(from), counts(to));
+        if old != new {
+            diff.trip_count_changes.push(TripCountChange {
+                route_id: id.clone(),
+                from: old,
+                to: new,
+            });
+        }
+    }
+    diff
+}
+
+fn route_name(r: &RouteInfo) -> String {
+    let names = [&r.route_short_name, &r.route_long_name]
+        .iter()
+        .filter_map(|n| n.as_deref())
+        .filter(|n| !n.is_empty())
+        .collect::<Vec<_>>();
+    format!("{} ({})", r.route_id, names.join(" "))
+}
+
+impl FeedDiff {
+    pub fn print_text(&self) {
+        println!(
+            "Changes from feed {} to feed {}",
+            self.from_feed_id, self.to_feed_id
+        );
+
+        let section = |title: &str, lines: Vec<String>| {
+            if !lines.is_empty() {
+                println!("{} ({}):", title, lines.len());
+                for l in lines {
+                    println!("  {}", l);
+                }
+            }
+        };
+        let stop = |s: &StopInfo| format!("{} ({})", s.stop_id, s.stop_name);
+        section("Added stops", self.added_stops.iter().map(stop).collect());
+        section(
+            "Removed stops",
+            self.removed_stops.iter().map(stop).collect(),
+        );
+        section(
+            "Renamed stops",
+            self.renamed_stops
+                .iter()
+                .map(|r| {
+                    format!(
+                        "{}: {} -> {}",
+                        r.to.stop_id, r.from.stop_name, r.to.stop_name
+                    )
+                })
+                .collect(),
+        );
+        section(
+            "Moved stops",
+            self.moved_stops
+                .iter()
+                .map(|m| format!("{} ({}): {:.0} m", m.stop_id, m.stop_name, m.distance))
+                .collect(),
+        );
+        section(
+            "Added routes",
+            self.added_routes.iter().map(route_name).collect(),
+        );
+        section(
+            "Removed routes",
+            self.removed_routes.iter().map(route_name).collect(),
+        );
+        section(
+            "Renamed routes",
+            self.renamed_routes
+                .iter()
+                .map(|r| format!("{} -> {}", route_name(&r.from), route_name(&r.to)))
+                .collect(),
+        );
+        section(
+            "Trips per weekday/saturday/sunday",
+            self.trip_count_changes
+                .iter()
+                .map(|c| {
+                    format!(
+                        "{}: {}/{}/{} -> {}/{}/{}",
+                        c.route_id,
+                        c.from.weekday,
+                        c.from.saturday,
+                        c.from.sunday,
+                        c.to.weekday,
+                        c.to.saturday,
+                        c.to.sunday
+                    )
+                })
+                .collect(),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stop(id: &str, name: &str, position: (f64, f64)) -> StopInfo {
+        StopInfo {
+            stop_id: id.into(),
+            stop_name: name.into(),
+            position,
+        }
+    }
+    fn route(id: &str, short_name: &str) -> RouteInfo {
+        RouteInfo {
+            route_id: id.into(),
+            route_short_name: Some(short_name.into()),
+            route_long_name: None,
+        }
+    }
+    fn content(stops: Vec<StopInfo>, routes: Vec<RouteInfo>) -> FeedContent {
+        FeedContent {
+            feed_id: 0,
+            stops: stops.into_iter().map(|s| (s.stop_id.clone(), s)).collect(),
+            routes: routes
+                .into_iter()
+                .map(|r| (r.route_id.clone(), r))
+                .collect(),
+            trip_counts: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn changes() {
+        let mut from = content(
+            vec![
+                stop("a", "A", (-36.8, 174.7)),
+                stop("b", "B", (-36.8, 174.7)),
+                stop("c", "C", (-36.8, 174.7)),
+            ],
+            vec![route("1", "1"), route("2", "2")],
+        );
+        from.trip_counts.insert(
+            "1".into(),
+            TripCounts {
+                weekday: 10,
+                saturday: 5,
+                sunday: 0,
+            },
+        );
+        let mut to = content(
+            vec![
+                stop("a", "A", (-36.8, 174.7)),
+                stop("b", "B Street", (-36.8, 174.7)),
+                stop("c", "C", (-36.801, 174.7)),
+                stop("d", "D", (-36.8, 174.7)),
+            ],
+            vec![route("1", "1X"), route("3", "3")],
+        );
+        to.trip_counts.insert(
+            "1".into(),
+            TripCounts {
+                weekday: 12,
+                saturday: 5,
+                sunday: 0,
+            },
+        );
+
+        let d = diff(&from, &to, 50.0);
+        assert_eq!(d.added_stops, vec![stop("d", "D", (-36.8, 174.7))]);
+        assert!(d.removed_stops.is_empty());
+        assert_eq!(d.renamed_stops.len(), 1);
+        assert_eq!(d.renamed_stops[0].to.stop_name, "B Street");
+        assert_eq!(d.moved_stops.len(), 1);
+        assert_eq!(d.moved_stops[0].stop_id, "c");
+        assert!((d.moved_stops[0].distance - 111.0).abs() < 1.0);
+        assert_eq!(d.added_routes, vec![route("3", "3")]);
+        assert_eq!(d.removed_routes, vec![route("2", "2")]);
+        assert_eq!(d.renamed_routes.len(), 1);
+        assert_eq!(d.trip_count_changes.len(), 1);
+        assert_eq!(d.trip_count_changes[0].to.weekday, 12);
+    }
+}
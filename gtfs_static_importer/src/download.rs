@@ -0,0 +1,211 @@
+use reqwest::header::{
+    HeaderMap, HeaderName, HeaderValue, ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, IF_RANGE,
+    LAST_MODIFIED, RANGE,
+};
+use reqwest::StatusCode;
+use tokio::io::AsyncWriteExt;
+
+use std::io::{Seek, SeekFrom};
+use std::path::Path;
+
+use crate::utils;
+use crate::{FeedMetadata, ImporterError};
+
+/// Parses `Name: value` pairs given on the command line into headers.
+pub fn parse_headers(headers: &[String]) -> Result<HeaderMap, ImporterError> {
+    let mut map = HeaderMap::new();
+    for h in headers {
+        let mut parts = h.splitn(2, ':');
+        let (name, value) = match (parts.next(), parts.next()) {
+            (Some(name), Some(value)) => (name.trim(), value.trim()),
+            _ => return Err(ImporterError::InvalidHeader(h.clone())),
+        };
+        map.insert(
+            HeaderName::from_bytes(name.as_bytes())
+                .map_err(|_| ImporterError::InvalidHeader(h.clone()))?,
+            HeaderValue::from_str(value).map_err(|_| ImporterError::InvalidHeader(h.clone()))?,
+        );
+    }
+    Ok(map)
+}
+
+/// How many times a download is tried, resuming where the previous try
+/// stopped if the server supports range requests.
+const DOWNLOAD_ATTEMPTS: u32 = 5;
+
+/// Downloads a zip file to a temporary file.
+///
+/// The ETag and Last-Modified values of `previous` are sent as a
+/// conditional request; `None` is returned if the server reports that the
+/// file has not changed. The returned metadata holds the new values.
+///
+/// If the connection fails, or ends before the whole file was received, the
+/// download is resumed with a Range request.
+pub async fn download_zip(
+    url: reqwest::Url,
+    headers: HeaderMap,
+    previous: &FeedMetadata,
+) -> Result<Option<(std::fs::File, FeedMetadata)>, ImporterError> {
+    let client = reqwest::Client::new();
+    let mut file = tokio::fs::File::from_std(tempfile::tempfile()?);
+    let mut written = 0;
+    let mut total = None;
+    // set by the first response
+    let mut metadata: Option<FeedMetadata> = None;
+    let mut bar = indicatif::ProgressBar::hidden();
+
+    for attempt in 1..=DOWNLOAD_ATTEMPTS {
+        let mut request = client.get(url.clone()).headers(headers.clone());
+        match &metadata {
+            None => {
+                if let Some(etag) = &previous.etag {
+                    request = request.header(IF_NONE_MATCH, etag);
+                }
+                if let Some(last_modified) = &previous.last_modified {
+                    request = request.header(IF_MODIFIED_SINCE, last_modified);
+                }
+            }
+            Some(m) => {
+                request = request.header(RANGE, format!("bytes={}-", written));
+                // the server sends the whole file if it has changed since
+                if let Some(validator) = m.etag.as_ref().or(m.last_modified.as_ref()) {
+                    request = request.header(IF_RANGE, validator);
+                }
+            }
+        }
+
+        let result = async {
+            let mut response = request.send().await?.error_for_status()?;
+            match response.status() {
+                StatusCode::NOT_MODIFIED if metadata.is_none() => return Ok(false),
+                StatusCode::PARTIAL_CONTENT if metadata.is_some() => {
+                    bar.println(format!("Resuming download at {} bytes", written));
+                }
+                _ => {
+                    // a new download, or the server ignored the range
+                    file.set_len(0).await?;
+                    file.seek(SeekFrom::Start(0)).await?;
+                    written = 0;
+
+                    let header = |name| {
+                        response
+                            .headers()
+                            .get(name)
+                            .and_then(|v: &HeaderValue| v.to_str().ok())
+                            .map(|v| v.to_string())
+                    };
+                    metadata = Some(FeedMetadata {
+                        source: previous.source.clone(),
+                        etag: header(ETAG),
+                        last_modified: header(LAST_MODIFIED),
+                    });
+                    total = response.content_length();
+                    bar.finish_and_clear();
+                    bar = match total {
+                        Some(l) => utils::progress_bar(
+                            l,
+                            "Downloading {spinner} [{elapsed_precise}] [{bar:60.yellow}] {bytes}/{total_bytes}",
+                        ),
+                        None => utils::spinner("Downloading {spinner} [{elapsed_precise}] {bytes}"),
+                    };
+                }
+            }
+
+            while let Some(chunk) = response.chunk().await? {
+                file.write_all(&chunk).await?;
+                written += chunk.len() as u64;
+                bar.set_position(written);
+            }
+            Ok::<_, ImporterError>(true)
+        }
+        .await;
+
+        // without a length, the download is complete when the response ends
+        let complete = match total {
+            Some(total) => written >= total,
+            None => true,
+        };
+        match result {
+            Ok(false) => return Ok(None),
+            Ok(true) if complete => {
+                bar.finish_and_clear();
+                file.flush().await?;
+                let file = file.into_std().await;
+                return Ok(metadata.map(|m| (file, m)));
+            }
+            Ok(true) => {
+                bar.println(format!(
+                    "Download ended after {} of {} bytes",
+                    written,
+                    total.unwrap_or_default()
+                ));
+            }
+            // a connection error, rather than an error status from the server
+            Err(ImporterError::Http(e)) if attempt < DOWNLOAD_ATTEMPTS && !e.is_status() => {
+                bar.println(format!("Download failed: {}", e));
+            }
+            Err(e) => {
+                bar.finish_and_clear();
+                return Err(e);
+            }
+        }
+        tokio::time::delay_for(std::time::Duration::from_secs(attempt.into())).await;
+    }
+    bar.finish_and_clear();
+    Err(ImporterError::DownloadIncomplete(url.to_string()))
+}
+
+/// Checks that a zip file is complete and not corrupted, by reading every
+/// file in it, which checks their CRC-32 checksums.
+pub fn verify_zip(file: &mut std::fs::File) -> Result<(), ImporterError> {
+    let invalid = |e: &dyn std::fmt::Display| ImporterError::InvalidZip(e.to_string());
+    file.seek(SeekFrom::Start(0))?;
+    {
+        let mut zip = zip::ZipArchive::new(&mut *file).map_err(|e| invalid(&e))?;
+        for i in 0..zip.len() {
+            let mut inner = zip.by_index(i).map_err(|e| invalid(&e))?;
+            std::io::copy(&mut inner, &mut std::io::sink()).map_err(|e| invalid(&e))?;
+        }
+    }
+    file.seek(SeekFrom::Start(0))?;
+    Ok(())
+}
+
+/// Extracts a zip file into a new temporary directory, which is deleted
+/// when the returned value is dropped.
+pub fn extract_zip(file: std::fs::File) -> Result<tempfile::TempDir, ImporterError> {
+    let temp_folder = tempfile::tempdir()?;
+    let temp_folder_path = temp_folder.path();
+
+    let mut zip = zip::ZipArchive::new(file)?;
+
+    let bar = utils::progress_bar(
+        zip.len() as u64,
+        "Writing {spinner} [{elapsed_precise}] [{bar:60.yellow}] {pos}/{len}",
+    );
+
+    for i in 0..zip.len() {
+        let mut inner = zip.by_index(i)?;
+        if inner.is_dir() {
+            bar.inc(1);
+            continue;
+        }
+
+        // feeds are sometimes zipped with their enclosing folder, only the
+        // file name is kept.
+        let file_name = match inner.sanitized_name().file_name() {
+            Some(f) => Path::new(f).to_path_buf(),
+            None => continue,
+        };
+
+        bar.println(format!("Writing {}", file_name.display()));
+
+        let file_path = temp_folder_path.join(file_name);
+        let mut new_file = std::fs::File::create(file_path)?;
+
+        std::io::copy(&mut inner, &mut new_file)?;
+        bar.inc(1);
+    }
+    bar.finish_and_clear();
+    Ok(temp_folder)
+}
@@ -0,0 +1,76 @@
+use futures::TryStreamExt;
+use tokio_postgres::{Client, IsolationLevel};
+use zip::write::{FileOptions, ZipWriter};
+
+use std::io::Write;
+use std::path::Path;
+
+use crate::{columns, stop_times, utils};
+use crate::{ImporterError, TABLE_AND_FILE_NAMES};
+
+/// Writes a feed in the database to a GTFS zip file at `out`.
+pub async fn export(client: &mut Client, feed_id: i32, out: &Path) -> Result<(), ImporterError> {
+    // one snapshot, so that the files agree with each other
+    let transaction = client
+        .build_transaction()
+        .isolation_level(IsolationLevel::RepeatableRead)
+        .read_only(true)
+        .start()
+        .await?;
+    if transaction
+        .query_opt("select 1 from feed where feed_id = $1", &[&feed_id])
+        .await?
+        .is_none()
+    {
+        return Err(ImporterError::FeedNotFound(feed_id));
+    }
+
+    let mut zip = ZipWriter::new(std::fs::File::create(out)?);
+    let options = FileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+
+    let bar = utils::progress_bar(
+        TABLE_AND_FILE_NAMES.len() as u64,
+        "Exporting {spinner} [{elapsed_precise}] [{bar:60.yellow}] {pos}/{len}",
+    );
+    for (file_name, table) in TABLE_AND_FILE_NAMES.iter() {
+        bar.println(format!("Writing {}", file_name));
+        let columns = columns::table_column_types(&transaction, table)
+            .await?
+            .into_iter()
+            .map(|(name, data_type)| select_expression(&name, &data_type))
+            .collect::<Vec<_>>();
+        let command = format!(
+            "copy (select {} from {} where feed_id = {}) to stdout with csv header",
+            columns.join(", "),
+            table,
+            feed_id
+        );
+
+        let mut content = Vec::new();
+        let stream = transaction.copy_out(&command[..]).await?;
+        futures::pin_mut!(stream);
+        while let Some(chunk) = stream.try_next().await? {
+            content.extend_from_slice(&chunk);
+        }
+        if *table == "stop_time" {
+            content = stop_times::format_times(&content)?;
+        }
+
+        zip.start_file(*file_name, options)?;
+        zip.write_all(&content)?;
+        bar.inc(1);
+    }
+    zip.finish()?;
+    bar.finish_and_clear();
+    Ok(())
+}
+
+/// Selects a column as it is written in a GTFS file.
+fn select_expression(column: &str, data_type: &str) -> String {
+    match data_type {
+        "date" => format!("to_char({0}, 'YYYYMMDD') as {0}", column),
+        // postgres writes booleans as t and f
+        "boolean" => format!("{0}::integer as {0}", column),
+        _ => column.to_string(),
+    }
+}
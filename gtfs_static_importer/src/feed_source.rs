@@ -0,0 +1,370 @@
+use async_trait::async_trait;
+use reqwest::header::HeaderMap;
+use serde::Deserialize;
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use crate::download;
+use crate::{FeedMetadata, ImporterError};
+
+/// Somewhere a GTFS feed can be fetched from.
+#[async_trait]
+pub trait FeedSource: Sync {
+    /// Stored as the feed's source, to find what was last imported from here.
+    fn id(&self) -> String;
+
+    /// Fetches the feed into a directory. Returns `None` if the feed is
+    /// known to be unchanged since `previous` was imported.
+    async fn fetch(&self, previous: &FeedMetadata) -> Result<Option<FetchedFeed>, ImporterError>;
+}
+
+/// An extracted feed, ready to be imported.
+pub struct FetchedFeed {
+    path: PathBuf,
+    // kept so that the temporary directory is deleted after the import
+    _temp_dir: Option<tempfile::TempDir>,
+    pub metadata: FeedMetadata,
+}
+
+impl FetchedFeed {
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Extracts a zip file, after checking that it is not corrupted.
+    async fn from_zip(
+        mut file: std::fs::File,
+        metadata: FeedMetadata,
+    ) -> Result<Self, ImporterError> {
+        let temp_dir = tokio::task::spawn_blocking(move || {
+            download::verify_zip(&mut file)?;
+            download::extract_zip(file)
+        })
+        .await??;
+        Ok(Self {
+            path: temp_dir.path().to_path_buf(),
+            _temp_dir: Some(temp_dir),
+            metadata,
+        })
+    }
+}
+
+/// A zip file downloaded from a url.
+pub struct UrlSource {
+    pub id: String,
+    pub url: reqwest::Url,
+    pub headers: HeaderMap,
+}
+
+impl UrlSource {
+    pub fn new(url: &str, headers: HeaderMap) -> Result<Self, ImporterError> {
+        Ok(Self {
+            id: url.to_string(),
+            url: reqwest::Url::parse(url)?,
+            headers,
+        })
+    }
+}
+
+#[async_trait]
+impl FeedSource for UrlSource {
+    fn id(&self) -> String {
+        self.id.clone()
+    }
+
+    async fn fetch(&self, previous: &FeedMetadata) -> Result<Option<FetchedFeed>, ImporterError> {
+        println!("Downloading {}", self.url);
+        match download::download_zip(self.url.clone(), self.headers.clone(), previous).await? {
+            Some((file, metadata)) => Ok(Some(FetchedFeed::from_zip(file, metadata).await?)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// An extracted feed in a directory, or a zip file.
+pub struct PathSource {
+    pub path: PathBuf,
+}
+
+#[async_trait]
+impl FeedSource for PathSource {
+    fn id(&self) -> String {
+        self.path
+            .canonicalize()
+            .unwrap_or_else(|_| self.path.clone())
+            .display()
+            .to_string()
+    }
+
+    async fn fetch(&self, previous: &FeedMetadata) -> Result<Option<FetchedFeed>, ImporterError> {
+        let mut metadata = FeedMetadata {
+            source: Some(self.id()),
+            ..Default::default()
+        };
+        if self.path.is_file() {
+            // the modification time stands in for Last-Modified
+            let modified = tokio::fs::metadata(&self.path).await?.modified()?;
+            let modified = chrono::DateTime::<chrono::Utc>::from(modified).to_rfc2822();
+            if previous.last_modified.as_ref() == Some(&modified) {
+                return Ok(None);
+            }
+            metadata.last_modified = Some(modified);
+            Ok(Some(
+                FetchedFeed::from_zip(std::fs::File::open(&self.path)?, metadata).await?,
+            ))
+        } else {
+            Ok(Some(FetchedFeed {
+                path: self.path.clone(),
+                _temp_dir: None,
+                metadata,
+            }))
+        }
+    }
+}
+
+/// A feed listed in a catalog in the format of the Mobility Database
+/// (https://database.mobilitydata.org), either its csv export or json.
+/// The catalog itself can be a url or a path.
+pub struct CatalogSource {
+    pub catalog: String,
+    /// mdb_source_id of the feed
+    pub id: String,
+    pub headers: HeaderMap,
+}
+
+impl CatalogSource {
+    /// Finds the download url of the feed in the catalog.
+    async fn find_url(&self) -> Result<String, ImporterError> {
+        let content = if self.catalog.starts_with("http://") || self.catalog.starts_with("https://")
+        {
+            reqwest::get(&self.catalog)
+                .await?
+                .error_for_status()?
+                .text()
+                .await?
+        } else {
+            tokio::fs::read_to_string(&self.catalog).await?
+        };
+
+        let url = if self.catalog.ends_with(".json") {
+            find_url_in_json(&content, &self.id)?
+        } else {
+            find_url_in_csv(&content, &self.id)?
+        };
+        url.ok_or_else(|| ImporterError::NotInCatalog(self.id.clone(), self.catalog.clone()))
+    }
+}
+
+#[async_trait]
+impl FeedSource for CatalogSource {
+    fn id(&self) -> String {
+        // not the url, which may change between versions of the catalog
+        format!("mdb:{}", self.id)
+    }
+
+    async fn fetch(&self, previous: &FeedMetadata) -> Result<Option<FetchedFeed>, ImporterError> {
+        let url = self.find_url().await?;
+        let source = UrlSource {
+            id: self.id(),
+            url: reqwest::Url::parse(&url)?,
+            headers: self.headers.clone(),
+        };
+        source.fetch(previous).await
+    }
+}
+
+/// The url to download from, preferring the catalog's hosted copy of the
+/// latest dataset over the agency's own url.
+fn find_url_in_csv(content: &str, id: &str) -> Result<Option<String>, ImporterError> {
+    let mut reader = csv::Reader::from_reader(content.as_bytes());
+    let headers = reader.headers()?.clone();
+    let column = |name| headers.iter().position(|h| h == name);
+    let id_column = match column("mdb_source_id") {
+        Some(c) => c,
+        None => return Ok(None),
+    };
+    let url_columns = [column("urls.latest"), column("urls.direct_download")];
+
+    for record in reader.records() {
+        let record = record?;
+        if record.get(id_column) == Some(id) {
+            return Ok(url_columns
+                .iter()
+                .filter_map(|c| c.and_then(|c| record.get(c)))
+                .find(|u| !u.is_empty())
+                .map(|u| u.to_string()));
+        }
+    }
+    Ok(None)
+}
+
+fn find_url_in_json(content: &str, id: &str) -> Result<Option<String>, ImporterError> {
+    #[derive(Deserialize)]
+    struct Urls {
+        latest: Option<String>,
+        direct_download: Option<String>,
+    }
+    #[derive(Deserialize)]
+    struct Entry {
+        mdb_source_id: serde_json::Value,
+        urls: Option<Urls>,
+    }
+
+    let entries: Vec<Entry> = serde_json::from_str(content)?;
+    let entry = entries.into_iter().find(|e| match &e.mdb_source_id {
+        serde_json::Value::String(s) => s == id,
+        serde_json::Value::Number(n) => n.as_u64().is_some() && n.as_u64() == id.parse().ok(),
+        _ => false,
+    });
+    Ok(entry
+        .and_then(|e| e.urls)
+        .and_then(|u| u.latest.or(u.direct_download))
+        .filter(|u| !u.is_empty()))
+}
+
+/// The feeds to download, read from a toml file like
+///
+/// ```toml
+/// [[feed]]
+/// name = "auckland"
+/// type = "url"
+/// url = "https://example.com/gtfs.zip"
+/// headers = { "Ocp-Apim-Subscription-Key" = "..." }
+///
+/// [[feed]]
+/// name = "wellington"
+/// type = "catalog"
+/// catalog = "https://bit.ly/catalogs-csv"
+/// id = "1234"
+///
+/// [[feed]]
+/// name = "local"
+/// type = "path"
+/// path = "/data/gtfs.zip"
+/// ```
+#[derive(Debug, Deserialize)]
+pub struct FeedsConfig {
+    #[serde(rename = "feed", default)]
+    pub feeds: Vec<FeedConfig>,
+}
+
+impl FeedsConfig {
+    pub fn read(path: &Path) -> Result<Self, ImporterError> {
+        Ok(toml::from_str(&std::fs::read_to_string(path)?)?)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FeedConfig {
+    pub name: String,
+    #[serde(flatten)]
+    pub source: SourceConfig,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SourceConfig {
+    Url {
+        url: String,
+        #[serde(default)]
+        headers: HashMap<String, String>,
+    },
+    Path {
+        path: PathBuf,
+    },
+    Catalog {
+        catalog: String,
+        id: String,
+        #[serde(default)]
+        headers: HashMap<String, String>,
+    },
+}
+
+impl FeedConfig {
+    pub fn source(&self) -> Result<Box<dyn FeedSource>, ImporterError> {
+        let header_map = |headers: &HashMap<String, String>| {
+            download::parse_headers(
+                &headers
+                    .iter()
+                    .map(|(k, v)| format!("{}: {}", k, v))
+                    .collect::<Vec<_>>(),
+            )
+        };
+        Ok(match &self.source {
+            SourceConfig::Url { url, headers } => {
+                Box::new(UrlSource::new(url, header_map(headers)?)?)
+            }
+            SourceConfig::Path { path } => Box::new(PathSource { path: path.clone() }),
+            SourceConfig::Catalog {
+                catalog,
+                id,
+                headers,
+            } => Box::new(CatalogSource {
+                catalog: catalog.clone(),
+                id: id.clone(),
+                headers: header_map(headers)?,
+            }),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn catalog_urls() {
+        let csv = "mdb_source_id,data_type,provider,urls.direct_download,urls.latest\n\
+                   1,gtfs,A,https://a.example/gtfs.zip,https://mdb.example/1/latest.zip\n\
+                   2,gtfs,B,https://b.example/gtfs.zip,\n";
+        assert_eq!(
+            find_url_in_csv(csv, "1").unwrap().as_deref(),
+            Some("https://mdb.example/1/latest.zip")
+        );
+        assert_eq!(
+            find_url_in_csv(csv, "2").unwrap().as_deref(),
+            Some("https://b.example/gtfs.zip")
+        );
+        assert_eq!(find_url_in_csv(csv, "3").unwrap(), None);
+
+        let json = r#"[
+            {"mdb_source_id": 1, "urls": {"direct_download": "https://a.example/gtfs.zip"}},
+            {"mdb_source_id": "mdb-2", "urls": {"latest": "https://mdb.example/2/latest.zip"}}
+        ]"#;
+        assert_eq!(
+            find_url_in_json(json, "1").unwrap().as_deref(),
+            Some("https://a.example/gtfs.zip")
+        );
+        assert_eq!(
+            find_url_in_json(json, "mdb-2").unwrap().as_deref(),
+            Some("https://mdb.example/2/latest.zip")
+        );
+    }
+
+    #[test]
+    fn config() {
+        let config: FeedsConfig = toml::from_str(
+            r#"
+            [[feed]]
+            name = "a"
+            type = "url"
+            url = "https://example.com/gtfs.zip"
+            headers = { "X-Key" = "k" }
+
+            [[feed]]
+            name = "b"
+            type = "catalog"
+            catalog = "catalog.csv"
+            id = "12"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.feeds.len(), 2);
+        assert_eq!(
+            config.feeds[0].source().unwrap().id(),
+            "https://example.com/gtfs.zip"
+        );
+        assert_eq!(config.feeds[1].source().unwrap().id(), "mdb:12");
+    }
+}
@@ -0,0 +1,138 @@
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::Serialize;
+use tokio_postgres::Client;
+
+use crate::{ImporterError, TABLE_AND_FILE_NAMES};
+
+/// A feed in the database, with what it was imported from.
+#[derive(Debug, Serialize)]
+pub struct FeedSummary {
+    pub feed_id: i32,
+    pub agencies: Vec<String>,
+    /// First and last day with any service, from calendar and calendar_date.
+    pub start_date: Option<NaiveDate>,
+    pub end_date: Option<NaiveDate>,
+    pub source: Option<String>,
+    pub imported_at: DateTime<Utc>,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    pub content_hash: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TableCount {
+    pub table: &'static str,
+    pub rows: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FeedDetails {
+    #[serde(flatten)]
+    pub summary: FeedSummary,
+    pub row_counts: Vec<TableCount>,
+}
+
+/// All feeds, or only the one with `feed_id`, in order of feed_id.
+pub async fn feed_summaries(
+    client: &Client,
+    feed_id: Option<i32>,
+) -> Result<Vec<FeedSummary>, ImporterError> {
+    let rows = client
+        .query(
+            "select f.feed_id,
+            (select array_agg(agency_name order by agency_name) from agency a
+                where a.feed_id = f.feed_id),
+            least(
+                (select min(start_date) from calendar c where c.feed_id = f.feed_id),
+                (select min(date) from calendar_date cd
+                    where cd.feed_id = f.feed_id and cd.exception_type = 1)),
+            greatest(
+                (select max(end_date) from calendar c where c.feed_id = f.feed_id),
+                (select max(date) from calendar_date cd
+                    where cd.feed_id = f.feed_id and cd.exception_type = 1)),
+            f.source, f.imported_at, f.etag, f.last_modified, f.content_hash
+        from feed f
+        where $1::integer is null or f.feed_id = $1
+        order by f.feed_id",
+            &[&feed_id],
+        )
+        .await?;
+    Ok(rows
+        .iter()
+        .map(|r| FeedSummary {
+            feed_id: r.get(0),
+            agencies: r.get::<_, Option<Vec<String>>>(1).unwrap_or_default(),
+            start_date: r.get(2),
+            end_date: r.get(3),
+            source: r.get(4),
+            imported_at: r.get(5),
+            etag: r.get(6),
+            last_modified: r.get(7),
+            content_hash: r.get(8),
+        })
+        .collect())
+}
+
+pub async fn feed_details(client: &Client, feed_id: i32) -> Result<FeedDetails, ImporterError> {
+    let summary = feed_summaries(client, Some(feed_id))
+        .await?
+        .pop()
+        .ok_or(ImporterError::FeedNotFound(feed_id))?;
+
+    let mut row_counts = Vec::new();
+    for (_, table) in TABLE_AND_FILE_NAMES.iter() {
+        let rows: i64 = client
+            .query_one(
+                &format!("select count(*) from {} where feed_id = $1", table)[..],
+                &[&feed_id],
+            )
+            .await?
+            .get(0);
+        row_counts.push(TableCount { table, rows });
+    }
+    Ok(FeedDetails {
+        summary,
+        row_counts,
+    })
+}
+
+fn date_range(summary: &FeedSummary) -> String {
+    match (summary.start_date, summary.end_date) {
+        (Some(s), Some(e)) => format!("{} to {}", s, e),
+        _ => "no service".into(),
+    }
+}
+
+pub fn print_summaries(summaries: &[FeedSummary]) {
+    println!(
+        "{:>7}  {:<24}  {:<24}  {:<30}  source",
+        "feed_id", "imported at", "service", "agencies"
+    );
+    for s in summaries {
+        println!(
+            "{:>7}  {:<24}  {:<24}  {:<30}  {}",
+            s.feed_id,
+            s.imported_at.format("%Y-%m-%d %H:%M:%S UTC").to_string(),
+            date_range(s),
+            s.agencies.join(", "),
+            s.source.as_deref().unwrap_or("")
+        );
+    }
+}
+
+pub fn print_details(details: &FeedDetails) {
+    let s = &details.summary;
+    let optional = |v: &Option<String>| v.clone().unwrap_or_else(|| "-".into());
+    println!("Feed {}", s.feed_id);
+    println!("  agencies:      {}", s.agencies.join(", "));
+    println!("  service:       {}", date_range(s));
+    println!("  source:        {}", optional(&s.source));
+    println!("  imported at:   {}", s.imported_at);
+    println!("  etag:          {}", optional(&s.etag));
+    println!("  last modified: {}", optional(&s.last_modified));
+    println!("  content hash:  {}", optional(&s.content_hash));
+    println!("  rows:");
+    for c in &details.row_counts {
+        println!("    {:<14} {}", c.table, c.rows);
+    }
+}
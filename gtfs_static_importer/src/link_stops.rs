@@ -0,0 +1,321 @@
+use serde::Deserialize;
+use tokio_postgres::Client;
+
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+use crate::stop_times::haversine_distance;
+use crate::ImporterError;
+
+/// A stop to be linked with stops of other feeds.
+#[derive(Debug, Clone, PartialEq)]
+pub struct LinkableStop {
+    pub feed_id: i32,
+    pub stop_id: String,
+    pub stop_name: String,
+    pub position: (f64, f64),
+    pub location_type: i32,
+}
+
+/// A stop's feed_id and stop_id.
+type StopKey = (i32, String);
+
+/// Linked stops, in the order of [`stop_pair`], and whether the link is
+/// from an override.
+type Links = HashMap<(StopKey, StopKey), bool>;
+
+/// A line of the override file, naming stops by the source of their feed,
+/// so that overrides still apply when a feed is imported again.
+#[derive(Debug, Deserialize)]
+struct Override {
+    source_a: String,
+    stop_id_a: String,
+    source_b: String,
+    stop_id_b: String,
+    /// 1 to link the stops, 0 to never link them
+    link: u8,
+}
+
+/// Similarity of two stop names from 0 to 1, the Sørensen–Dice coefficient
+/// of their character bigrams, ignoring case and punctuation.
+pub fn name_similarity(a: &str, b: &str) -> f64 {
+    fn bigrams(s: &str) -> Vec<(char, char)> {
+        let normalized = s
+            .chars()
+            .flat_map(char::to_lowercase)
+            .map(|c| if c.is_alphanumeric() { c } else { ' ' })
+            .collect::<String>();
+        let words = normalized.split_whitespace().collect::<Vec<_>>().join(" ");
+        let chars = words.chars().collect::<Vec<_>>();
+        let mut b = chars.windows(2).map(|w| (w[0], w[1])).collect::<Vec<_>>();
+        b.sort_unstable();
+        b
+    }
+    let (a, b) = (bigrams(a), bigrams(b));
+    if a.is_empty() || b.is_empty() {
+        return if a == b { 1.0 } else { 0.0 };
+    }
+    // count common bigrams, each at most as often as in both names
+    let (mut i, mut j, mut common) = (0, 0, 0);
+    while i < a.len() && j < b.len() {
+        if a[i] == b[j] {
+            common += 1;
+            i += 1;
+            j += 1;
+        } else if a[i] < b[j] {
+            i += 1;
+        } else {
+            j += 1;
+        }
+    }
+    2.0 * common as f64 / (a.len() + b.len()) as f64
+}
+
+/// Pairs of indices of stops in different feeds, of the same location_type,
+/// within `max_distance` metres of each other and with similar names.
+pub fn find_links(
+    stops: &[LinkableStop],
+    max_distance: f64,
+    min_similarity: f64,
+) -> Vec<(usize, usize)> {
+    // a degree of latitude is about 111 km everywhere
+    let max_lat_difference = max_distance / 111_000.0;
+
+    let mut order = (0..stops.len()).collect::<Vec<_>>();
+    order.sort_by(|&a, &b| {
+        stops[a]
+            .position
+            .0
+            .partial_cmp(&stops[b].position.0)
+            .unwrap_or(std::cmp::Ordering::Equal)
+    });
+
+    let mut links = Vec::new();
+    for (n, &i) in order.iter().enumerate() {
+        let a = &stops[i];
+        for &j in &order[n + 1..] {
+            let b = &stops[j];
+            if b.position.0 - a.position.0 > max_lat_difference {
+                break;
+            }
+            if a.feed_id != b.feed_id
+                && a.location_type == b.location_type
+                && haversine_distance(a.position, b.position) <= max_distance
+                && name_similarity(&a.stop_name, &b.stop_name) >= min_similarity
+            {
+                links.push((i.min(j), i.max(j)));
+            }
+        }
+    }
+    links.sort_unstable();
+    links
+}
+
+/// Orders a pair of stops, so that a link has the same key whichever way
+/// round it was found.
+fn stop_pair(a: StopKey, b: StopKey) -> (StopKey, StopKey) {
+    if a <= b {
+        (a, b)
+    } else {
+        (b, a)
+    }
+}
+
+fn stop_key(s: &LinkableStop) -> StopKey {
+    (s.feed_id, s.stop_id.clone())
+}
+
+/// The links found by [`find_links`], keyed by stop.
+fn automatic_links(stops: &[LinkableStop], found: &[(usize, usize)]) -> Links {
+    found
+        .iter()
+        .map(|&(i, j)| (stop_pair(stop_key(&stops[i]), stop_key(&stops[j])), false))
+        .collect()
+}
+
+/// Links or unlinks two stops. A link is only added if both stops exist.
+fn apply_override(
+    links: &mut Links,
+    stop_keys: &HashSet<StopKey>,
+    a: StopKey,
+    b: StopKey,
+    link: bool,
+) {
+    let pair = stop_pair(a, b);
+    if !link {
+        links.remove(&pair);
+    } else if stop_keys.contains(&pair.0) && stop_keys.contains(&pair.1) {
+        links.insert(pair, true);
+    }
+}
+
+/// Finds equivalent stops across all feeds and replaces the links in the
+/// stop_link table. Overrides in the csv file at `overrides` take precedence.
+pub async fn link_stops(
+    client: &mut Client,
+    max_distance: f64,
+    min_similarity: f64,
+    overrides: Option<&Path>,
+) -> Result<(), ImporterError> {
+    let transaction = client.transaction().await?;
+
+    // entrances and other nodes are not linked
+    let stops = transaction
+        .query(
+            "select feed_id, stop_id, stop_name, stop_lat, stop_lon, coalesce(location_type, 0)
+            from stop where coalesce(location_type, 0) in (0, 1)",
+            &[],
+        )
+        .await?
+        .iter()
+        .map(|r| LinkableStop {
+            feed_id: r.get(0),
+            stop_id: r.get(1),
+            stop_name: r.get(2),
+            position: (r.get(3), r.get(4)),
+            location_type: r.get(5),
+        })
+        .collect::<Vec<_>>();
+
+    let mut links = automatic_links(&stops, &find_links(&stops, max_distance, min_similarity));
+    println!("Found {} links between stops", links.len());
+
+    if let Some(path) = overrides {
+        let feeds = transaction
+            .query(
+                "select source, feed_id from feed where source is not null",
+                &[],
+            )
+            .await?
+            .iter()
+            .fold(HashMap::<String, Vec<i32>>::new(), |mut m, r| {
+                m.entry(r.get(0)).or_default().push(r.get(1));
+                m
+            });
+        let stop_keys = stops.iter().map(stop_key).collect::<HashSet<_>>();
+
+        let mut reader = csv::Reader::from_path(path)?;
+        for o in reader.deserialize() {
+            let o: Override = o?;
+            let no_feeds = Vec::new();
+            let feeds_a = feeds.get(&o.source_a).unwrap_or(&no_feeds);
+            let feeds_b = feeds.get(&o.source_b).unwrap_or(&no_feeds);
+            for &a in feeds_a {
+                for &b in feeds_b {
+                    apply_override(
+                        &mut links,
+                        &stop_keys,
+                        (a, o.stop_id_a.clone()),
+                        (b, o.stop_id_b.clone()),
+                        o.link != 0,
+                    );
+                }
+            }
+        }
+    }
+
+    let (mut feed_ids, mut stop_ids, mut linked_feed_ids, mut linked_stop_ids, mut manual) =
+        (vec![], vec![], vec![], vec![], vec![]);
+    for (((a_feed, a_stop), (b_feed, b_stop)), is_manual) in links.iter() {
+        for (from, to) in &[
+            ((a_feed, a_stop), (b_feed, b_stop)),
+            ((b_feed, b_stop), (a_feed, a_stop)),
+        ] {
+            feed_ids.push(*from.0);
+            stop_ids.push(from.1);
+            linked_feed_ids.push(*to.0);
+            linked_stop_ids.push(to.1);
+            manual.push(*is_manual);
+        }
+    }
+
+    transaction.execute("delete from stop_link", &[]).await?;
+    transaction
+        .execute(
+            "insert into stop_link (feed_id, stop_id, linked_feed_id, linked_stop_id, manual)
+        select * from unnest($1::integer[], $2::text[], $3::integer[], $4::text[], $5::boolean[])",
+            &[
+                &feed_ids,
+                &stop_ids,
+                &linked_feed_ids,
+                &linked_stop_ids,
+                &manual,
+            ],
+        )
+        .await?;
+    transaction.commit().await?;
+    println!("Linked {} pairs of stops", links.len());
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn similarity() {
+        assert_eq!(name_similarity("Britomart", "BRITOMART"), 1.0);
+        assert_eq!(
+            name_similarity("Britomart Station", "Britomart  station."),
+            1.0
+        );
+        assert!(name_similarity("Britomart", "Britomart Train Station") > 0.5);
+        assert!(name_similarity("Britomart", "Newmarket") < 0.3);
+        assert_eq!(name_similarity("", "Newmarket"), 0.0);
+    }
+
+    #[test]
+    fn links() {
+        let stop = |feed_id, stop_id: &str, stop_name: &str, position| LinkableStop {
+            feed_id,
+            stop_id: stop_id.into(),
+            stop_name: stop_name.into(),
+            position,
+            location_type: 0,
+        };
+        let stops = vec![
+            stop(1, "a", "Britomart", (-36.8443, 174.7676)),
+            stop(2, "b", "Britomart Station", (-36.8444, 174.7677)),
+            // same place, different name
+            stop(2, "c", "Customs Street", (-36.8443, 174.7676)),
+            // same name, too far
+            stop(2, "d", "Britomart", (-36.8543, 174.7676)),
+            // same feed
+            stop(1, "e", "Britomart", (-36.8443, 174.7676)),
+        ];
+        assert_eq!(find_links(&stops, 100.0, 0.5), vec![(0, 1), (1, 4)]);
+    }
+
+    #[test]
+    fn overrides() {
+        let stop = |feed_id, stop_id: &str, stop_name: &str| LinkableStop {
+            feed_id,
+            stop_id: stop_id.into(),
+            stop_name: stop_name.into(),
+            position: (-36.8443, 174.7676),
+            location_type: 0,
+        };
+        // in reverse key order, as the database may return them
+        let stops = vec![
+            stop(2, "d", "Customs Street"),
+            stop(2, "b", "Britomart"),
+            stop(1, "c", "Customs Street"),
+            stop(1, "a", "Britomart"),
+        ];
+        let key = |feed_id, stop_id: &str| (feed_id, stop_id.to_string());
+        let stop_keys = stops.iter().map(stop_key).collect::<HashSet<_>>();
+        let mut links = automatic_links(&stops, &find_links(&stops, 100.0, 0.5));
+        assert_eq!(links.len(), 2);
+
+        // never link Britomart
+        apply_override(&mut links, &stop_keys, key(1, "a"), key(2, "b"), false);
+        // link Customs Street manually, the other way round
+        apply_override(&mut links, &stop_keys, key(2, "d"), key(1, "c"), true);
+        // a stop which doesn't exist
+        apply_override(&mut links, &stop_keys, key(1, "a"), key(2, "x"), true);
+        assert_eq!(
+            links.into_iter().collect::<Vec<_>>(),
+            vec![((key(1, "c"), key(2, "d")), true)]
+        );
+    }
+}
@@ -1,18 +1,31 @@
-use bytes::buf::BufMutExt;
-use bytes::BytesMut;
 use dotenv::dotenv;
-use postgres::{Client, NoTls};
-use tokio::io::AsyncWriteExt;
+use tokio_postgres::Client;
 
 use std::error::Error;
-use std::io::Write;
+use std::num::NonZeroU32;
 use std::path::Path;
 
 use structopt::StructOpt;
 
 use derive_more::{Display, From};
 
+use feed_source::FeedSource;
+use subset::Subset;
+
+mod columns;
+mod diff;
+mod download;
+mod export;
+mod feed_source;
+mod feeds;
+mod link_stops;
+mod prune;
+mod service_dates;
+mod staging;
+mod stop_times;
+mod subset;
 mod utils;
+mod validate;
 
 static TABLE_AND_FILE_NAMES: [(&str, &str); 8] = [
     ("shapes.txt", "shape"),
@@ -25,10 +38,14 @@ static TABLE_AND_FILE_NAMES: [(&str, &str); 8] = [
     ("stop_times.txt", "stop_time"),
 ];
 
+/// Tables computed from the others when a feed is imported, partitioned by
+/// feed like them.
+static DERIVED_TABLES: [&str; 1] = ["service_date"];
+
 #[derive(Debug, From, Display)]
 enum ImporterError {
     #[display(fmt = "Database error: {}", _0)]
-    DbError(postgres::Error),
+    DbError(tokio_postgres::Error),
     #[display(fmt = "File error: {}", _0)]
     FileError(std::io::Error),
     #[display(fmt = "{} file should have data", _0)]
@@ -43,245 +60,634 @@ enum ImporterError {
     Zip(zip::result::ZipError),
     #[display(fmt = "Tokio error: {}", _0)]
     TokioJoin(tokio::task::JoinError),
+    #[display(fmt = "{}, line {}: {}", _0, _1, _2)]
+    InvalidStopTime(String, u64, String),
+    #[display(fmt = "Validation found {} errors", _0)]
+    #[from(ignore)]
+    ValidationFailed(usize),
+    #[display(fmt = "Error writing json: {}", _0)]
+    Json(serde_json::Error),
+    #[display(fmt = "Invalid header \"{}\", expected \"Name: value\"", _0)]
+    #[from(ignore)]
+    InvalidHeader(String),
+    #[display(fmt = "Invalid url: {}", _0)]
+    Url(url::ParseError),
+    #[display(fmt = "Error reading config: {}", _0)]
+    Config(toml::de::Error),
+    #[display(fmt = "Feed {} not found in catalog {}", _0, _1)]
+    NotInCatalog(String, String),
+    #[display(fmt = "No feed named {} in config", _0)]
+    #[from(ignore)]
+    UnknownFeed(String),
+    #[display(fmt = "No feed with feed_id {}", _0)]
+    #[from(ignore)]
+    FeedNotFound(i32),
+    #[display(fmt = "No trips match {}", _0)]
+    #[from(ignore)]
+    EmptySubset(String),
+    #[display(fmt = "Download of {} did not finish", _0)]
+    #[from(ignore)]
+    DownloadIncomplete(String),
+    #[display(fmt = "Invalid zip file: {}", _0)]
+    #[from(ignore)]
+    InvalidZip(String),
+    #[display(fmt = "{} feeds could not be downloaded", _0)]
+    #[from(ignore)]
+    DownloadsFailed(usize),
 }
 impl Error for ImporterError {}
 
+/// Where a feed came from, stored in the feed table.
+#[derive(Debug, Default, Clone)]
+struct FeedMetadata {
+    /// The url or path that the feed was imported from.
+    source: Option<String>,
+    /// Values of the ETag and Last-Modified response headers, if the feed
+    /// was downloaded, to avoid downloading it again if it has not changed.
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
 #[derive(StructOpt, Debug)]
 #[structopt(name = "gtfs_postgres_importer")]
 enum Options {
+    /// Imports a feed from a directory, a zip file or a url
     Import {
-        #[structopt(short, long)]
-        path: String,
+        /// A directory or a zip file
+        #[structopt(short, long, required_unless = "url")]
+        path: Option<String>,
+        /// Url of a zip file to download
+        #[structopt(short, long, conflicts_with = "path")]
+        url: Option<String>,
+        /// Header to send with the download request, as "Name: value"
+        #[structopt(short = "H", long = "header", requires = "url")]
+        headers: Vec<String>,
+        #[structopt(flatten)]
+        import: ImportOptions,
     },
+    /// Downloads and imports feeds which have changed since their last import
     Download {
-        #[structopt(short = "f", long)]
-        tf_feed_id: String,
+        /// Feed id on transitfeeds.com, needs TRANSITFEEDS_KEY to be set
+        #[structopt(short = "f", long, required_unless = "config")]
+        tf_feed_id: Option<String>,
+        /// Toml file listing the feeds to download
+        #[structopt(short, long, conflicts_with = "tf-feed-id")]
+        config: Option<String>,
+        /// Only download the feed with this name in the config
+        #[structopt(short, long, requires = "config")]
+        name: Option<String>,
+        #[structopt(flatten)]
+        import: ImportOptions,
     },
     DeleteFeed {
         #[structopt(short = "f", long)]
         feed_id: u32,
     },
+    /// Deletes old feeds
+    Prune {
+        /// Delete feeds whose service ended more than this many days ago
+        #[structopt(long, required_unless = "keep-latest")]
+        expired_days: Option<u32>,
+        /// Delete feeds of which each agency has this many newer feeds
+        #[structopt(long)]
+        keep_latest: Option<NonZeroU32>,
+        /// List the feeds that would be deleted without deleting them
+        #[structopt(long)]
+        dry_run: bool,
+    },
+    /// Lists the feeds in the database
+    ListFeeds {
+        /// Print the feeds as json
+        #[structopt(long)]
+        json: bool,
+    },
+    /// Shows a feed's metadata and the number of rows in each table
+    ShowFeed {
+        #[structopt(short = "f", long)]
+        feed_id: i32,
+        /// Print the feed as json
+        #[structopt(long)]
+        json: bool,
+    },
+    /// Reports what changed between two feeds, e.g. two versions of a timetable
+    Diff {
+        #[structopt(long)]
+        from: i32,
+        #[structopt(long)]
+        to: i32,
+        /// Report stops which moved more than this many metres
+        #[structopt(long, default_value = "50")]
+        moved_distance: f64,
+        /// Print the report as json
+        #[structopt(long)]
+        json: bool,
+    },
+    /// Writes a feed in the database to a GTFS zip file
+    Export {
+        #[structopt(short = "f", long)]
+        feed_id: i32,
+        /// Path of the zip file to write
+        #[structopt(short, long)]
+        out: String,
+    },
+    /// Links stops of different feeds which are at the same place, so that
+    /// their departures are shown together. Run it again after imports.
+    LinkStops {
+        /// Largest distance in metres between stops to link
+        #[structopt(long, default_value = "100")]
+        max_distance: f64,
+        /// Smallest similarity of names (from 0 to 1) of stops to link
+        #[structopt(long, default_value = "0.5")]
+        min_similarity: f64,
+        /// Csv file with columns source_a,stop_id_a,source_b,stop_id_b,link
+        /// where link is 1 to link the stops or 0 to never link them
+        #[structopt(long)]
+        overrides: Option<String>,
+    },
+    /// Checks a GTFS feed in a directory without importing it
+    Validate {
+        #[structopt(short, long)]
+        path: String,
+        /// Print the report as json
+        #[structopt(long)]
+        json: bool,
+    },
+}
+
+/// Options of Import and Download.
+#[derive(StructOpt, Debug)]
+struct ImportOptions {
+    /// Download and import even if the feed has not changed since its last
+    /// import, or an identical feed has already been imported
+    #[structopt(long)]
+    force: bool,
+    /// Number of database connections to load tables on at once
+    #[structopt(long, default_value = "4")]
+    jobs: usize,
+    #[structopt(flatten)]
+    subset: Subset,
+    /// Set from DATABASE_URL, to open more connections
+    #[structopt(skip)]
+    db_url: String,
 }
 
-fn main() {
-    match run() {
-        Ok(()) => println!("Successful!"),
+#[tokio::main]
+async fn main() {
+    match run().await {
+        Ok(()) => eprintln!("Successful!"),
         Err(e) => eprintln!("{}", e),
     }
 }
-fn run() -> Result<(), ImporterError> {
-    let options = Options::from_args();
+async fn run() -> Result<(), ImporterError> {
+    let mut options = Options::from_args();
 
     dotenv().ok();
 
+    // validation does not need a database
+    if let Options::Validate { path, json } = &options {
+        return validate(Path::new(path), *json);
+    }
+
     let db_url = &std::env::var("DATABASE_URL")
         .map_err(|e| ImporterError::EnvVar("DATABASE_URL".into(), e))?;
 
-    println!("Connecting to {}", db_url);
-    let mut client = postgres::Client::connect(db_url, NoTls)?;
+    // not on stdout, which may be parsed as json
+    eprintln!("Connecting to {}", db_url);
+    let mut client = utils::connect(db_url).await?;
+
+    match &mut options {
+        Options::Import { import, .. } => import.db_url = db_url.clone(),
+        Options::Download { import, .. } => import.db_url = db_url.clone(),
+        _ => {}
+    }
 
     match options {
-        Options::Import { path } => import(&Path::new(&path), &mut client),
-        Options::DeleteFeed { feed_id } => delete_feed(feed_id, &mut client),
-        Options::Download { tf_feed_id } => download(tf_feed_id, &mut client),
+        Options::Import {
+            path: Some(path),
+            import,
+            ..
+        } => import_path(Path::new(&path), &import, &mut client).await,
+        Options::Import {
+            url: Some(url),
+            headers,
+            import,
+            ..
+        } => import_url(&url, &headers, &import, &mut client).await,
+        Options::Import { .. } => unreachable!(), // ensured by structopt
+        Options::DeleteFeed { feed_id } => delete_feed(feed_id, &mut client).await,
+        Options::Prune {
+            expired_days,
+            keep_latest,
+            dry_run,
+        } => prune(expired_days, keep_latest, dry_run, &mut client).await,
+        Options::ListFeeds { json } => list_feeds(json, &client).await,
+        Options::ShowFeed { feed_id, json } => show_feed(feed_id, json, &client).await,
+        Options::Diff {
+            from,
+            to,
+            moved_distance,
+            json,
+        } => diff(from, to, moved_distance, json, &client).await,
+        Options::LinkStops {
+            max_distance,
+            min_similarity,
+            overrides,
+        } => {
+            link_stops::link_stops(
+                &mut client,
+                max_distance,
+                min_similarity,
+                overrides.as_deref().map(Path::new),
+            )
+            .await
+        }
+        Options::Export { feed_id, out } => {
+            export::export(&mut client, feed_id, Path::new(&out)).await
+        }
+        Options::Download {
+            tf_feed_id: Some(tf_feed_id),
+            import,
+            ..
+        } => download_transitfeeds(tf_feed_id, &import, &mut client).await,
+        Options::Download {
+            config: Some(config),
+            name,
+            import,
+            ..
+        } => download_all(Path::new(&config), name.as_deref(), &import, &mut client).await,
+        Options::Download { .. } => unreachable!(), // ensured by structopt
+        Options::Validate { .. } => unreachable!(),
+    }
+}
+
+fn validate(path: &Path, json: bool) -> Result<(), ImporterError> {
+    let report = validate::validate(path, chrono::Local::now().naive_local().date())?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        report.print_text();
+    }
+    match report.errors {
+        0 => Ok(()),
+        n => Err(ImporterError::ValidationFailed(n)),
+    }
+}
+async fn list_feeds(json: bool, client: &Client) -> Result<(), ImporterError> {
+    let summaries = feeds::feed_summaries(client, None).await?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&summaries)?);
+    } else {
+        feeds::print_summaries(&summaries);
+    }
+    Ok(())
+}
+
+async fn show_feed(feed_id: i32, json: bool, client: &Client) -> Result<(), ImporterError> {
+    let details = feeds::feed_details(client, feed_id).await?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&details)?);
+    } else {
+        feeds::print_details(&details);
+    }
+    Ok(())
+}
+
+async fn diff(
+    from: i32,
+    to: i32,
+    moved_distance: f64,
+    json: bool,
+    client: &Client,
+) -> Result<(), ImporterError> {
+    let report = diff::diff(
+        &diff::load_feed(client, from).await?,
+        &diff::load_feed(client, to).await?,
+        moved_distance,
+    );
+    if json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        report.print_text();
     }
+    Ok(())
+}
+
+async fn prune(
+    expired_days: Option<u32>,
+    keep_latest: Option<NonZeroU32>,
+    dry_run: bool,
+    client: &mut Client,
+) -> Result<(), ImporterError> {
+    let summaries = feeds::feed_summaries(client, None).await?;
+    let today = chrono::Local::now().naive_local().date();
+    let pruned = prune::feeds_to_prune(&summaries, today, expired_days, keep_latest);
+
+    if pruned.is_empty() {
+        println!("No feeds to delete");
+    }
+    for (feed_id, reason) in pruned {
+        if dry_run {
+            println!("Would delete feed {}: {}", feed_id, reason);
+        } else {
+            println!("Deleting feed {}: {}", feed_id, reason);
+            delete_feed(feed_id as u32, client).await?;
+        }
+    }
+    Ok(())
 }
-// todo async
-fn delete_feed(feed_id: u32, client: &mut Client) -> Result<(), ImporterError> {
-    let mut transaction = client.transaction()?;
+
+async fn delete_feed(feed_id: u32, client: &mut Client) -> Result<(), ImporterError> {
+    let transaction = client.transaction().await?;
     println!("Deleting data with feed_id = {}", feed_id);
 
     let bar = utils::progress_bar(
-        TABLE_AND_FILE_NAMES.len() as u64,
+        (DERIVED_TABLES.len() + TABLE_AND_FILE_NAMES.len()) as u64,
         "Deleting {spinner} [{elapsed_precise}] [{bar:60.yellow}] {pos}/{len}",
     );
     // rev to avoid foreign key violations
-    for s in TABLE_AND_FILE_NAMES.iter().rev() {
-        bar.println(format!("Deleting from table {}", &s.1));
-        transaction.execute(
-            &format!("delete from {} where feed_id={}", &s.1, &feed_id)[..],
-            &[],
-        )?;
+    let tables = DERIVED_TABLES
+        .iter()
+        .chain(TABLE_AND_FILE_NAMES.iter().rev().map(|s| &s.1));
+    for table in tables {
+        let partition = partition_name(table, feed_id as i32);
+        bar.println(format!("Dropping table {}", &partition));
+        transaction
+            .batch_execute(
+                &format!(
+                    "alter table {0} detach partition {1}; drop table {1};",
+                    table, partition
+                )[..],
+            )
+            .await?;
         bar.inc(1);
     }
-    transaction.execute(
-        &format!("delete from feed where feed_id={}", &feed_id)[..],
-        &[],
-    )?;
-    transaction.commit()?;
+    transaction
+        .execute(
+            &format!("delete from feed where feed_id={}", &feed_id)[..],
+            &[],
+        )
+        .await?;
+    transaction.commit().await?;
     bar.finish_and_clear();
 
     Ok(())
 }
 
-fn download(feed_id: String, client: &mut Client) -> Result<(), ImporterError> {
+async fn download_transitfeeds(
+    feed_id: String,
+    options: &ImportOptions,
+    client: &mut Client,
+) -> Result<(), ImporterError> {
     let tf_key = &std::env::var("TRANSITFEEDS_KEY")
         .map_err(|e| ImporterError::EnvVar("TRANSITFEEDS_KEY".into(), e))?;
 
-    let temp_file = tempfile::tempfile()?;
-    let mut async_file = tokio::fs::File::from_std(temp_file);
-
-    let mut runtime = tokio::runtime::Runtime::new()?;
-
-    println!("Downloading latest feed");
+    let source = feed_source::UrlSource {
+        // the url is not stored, as it contains the api key
+        id: format!("transitfeeds:{}", feed_id),
+        url: reqwest::Url::parse_with_params(
+            "https://api.transitfeeds.com/v1/getLatestFeedVersion",
+            &[("key", &tf_key[..]), ("feed", &feed_id[..])],
+        )?,
+        headers: Default::default(),
+    };
+    download(&source, options, client).await
+}
 
-    // TODO: workaround, reqwest has no blocking Response::chunk()
-    runtime.block_on(async {
-        let client = reqwest::Client::new();
+/// Downloads every feed in the config (or only the one called `name`).
+/// A failure does not stop the other feeds from being downloaded.
+async fn download_all(
+    config_path: &Path,
+    name: Option<&str>,
+    options: &ImportOptions,
+    client: &mut Client,
+) -> Result<(), ImporterError> {
+    let config = feed_source::FeedsConfig::read(config_path)?;
+    let feeds = config
+        .feeds
+        .iter()
+        .filter(|f| name.is_none() || name == Some(&f.name[..]))
+        .collect::<Vec<_>>();
+    if let (Some(name), true) = (name, feeds.is_empty()) {
+        return Err(ImporterError::UnknownFeed(name.to_string()));
+    }
 
-        let mut response = client
-            .get("https://api.transitfeeds.com/v1/getLatestFeedVersion")
-            .query(&[("key".to_string(), tf_key), ("feed".to_string(), &feed_id)])
-            .send()
-            .await?;
-        dbg!(response.headers());
-        if let Some(l) = response.content_length() {
-            let bar = utils::progress_bar(
-                l as u64,
-                "Downloading {spinner} [{elapsed_precise}] [{bar:60.yellow}] {bytes}/{total_bytes}",
-            );
-            while let Some(chunk) = response.chunk().await? {
-                bar.inc(chunk.len() as u64);
-                async_file.write(&chunk).await?;
-            }
+    let mut failed = 0;
+    for feed in feeds {
+        println!("Feed {}", feed.name);
+        let result = match feed.source() {
+            Ok(source) => download(source.as_ref(), options, client).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = result {
+            eprintln!("Error downloading feed {}: {}", feed.name, e);
+            failed += 1;
         }
-        Ok::<(), ImporterError>(())
-    })?;
-
-    // unwrap should work, as we have finished all io operations.
-
-    let temp_folder = tempfile::tempdir()?;
-    let temp_folder_path = temp_folder.path();
-
-    let mut zip = zip::ZipArchive::new(async_file.try_into_std().unwrap())?;
-
-    let bar = utils::progress_bar(
-        zip.len() as u64,
-        "Writing {spinner} [{elapsed_precise}] [{bar:60.yellow}] {pos}/{len}",
-    );
-
-    for i in 0..zip.len() {
-        let mut inner = zip.by_index(i)?;
-
-        let file_name = inner.sanitized_name();
-
-        bar.println(format!("Writing {}", file_name.display()));
+    }
+    match failed {
+        0 => Ok(()),
+        n => Err(ImporterError::DownloadsFailed(n)),
+    }
+}
 
-        let file_path = std::path::PathBuf::from(temp_folder_path).join(file_name);
-        let mut new_file = std::fs::File::create(file_path)?;
+async fn import_url(
+    url: &str,
+    headers: &[String],
+    options: &ImportOptions,
+    client: &mut Client,
+) -> Result<(), ImporterError> {
+    let source = feed_source::UrlSource::new(url, download::parse_headers(headers)?)?;
+    download(&source, options, client).await
+}
 
-        std::io::copy(&mut inner, &mut new_file)?;
-        bar.inc(1);
+/// Fetches a feed and imports it, unless it is unchanged since the last
+/// import from the same source, or `force` is set.
+async fn download(
+    source: &dyn FeedSource,
+    options: &ImportOptions,
+    client: &mut Client,
+) -> Result<(), ImporterError> {
+    let previous = if options.force {
+        FeedMetadata {
+            source: Some(source.id()),
+            ..Default::default()
+        }
+    } else {
+        latest_feed_metadata(&source.id(), client).await?
+    };
+
+    match source.fetch(&previous).await? {
+        Some(feed) => import(feed.path(), &feed.metadata, options, client).await,
+        None => {
+            println!("Feed has not changed since the last import");
+            Ok(())
+        }
     }
-    bar.finish_and_clear();
-    import(temp_folder_path, client)?;
+}
 
-    Ok(())
+/// Metadata of the feed last imported from `source`, which only has the
+/// source set if there is no such feed.
+async fn latest_feed_metadata(
+    source: &str,
+    client: &Client,
+) -> Result<FeedMetadata, ImporterError> {
+    let row = client
+        .query_opt(
+            "select etag, last_modified from feed where source = $1 order by feed_id desc limit 1",
+            &[&source],
+        )
+        .await?;
+    Ok(FeedMetadata {
+        source: Some(source.to_string()),
+        etag: row.as_ref().and_then(|r| r.get(0)),
+        last_modified: row.as_ref().and_then(|r| r.get(1)),
+    })
 }
 
-fn import(path: &Path, client: &mut Client) -> Result<(), ImporterError> {
-    println!("Importing data");
+async fn import_path(
+    path: &Path,
+    options: &ImportOptions,
+    client: &mut Client,
+) -> Result<(), ImporterError> {
+    let source = feed_source::PathSource {
+        path: path.to_path_buf(),
+    };
+    // always imported, as the path was given explicitly
+    let previous = FeedMetadata {
+        source: Some(source.id()),
+        ..Default::default()
+    };
+    let feed = source.fetch(&previous).await?.unwrap();
+    import(feed.path(), &feed.metadata, options, client).await
+}
 
-    let mut transaction = client.transaction()?;
+/// Name of the table which is the partition of `table` with a feed's data.
+fn partition_name(table: &str, feed_id: i32) -> String {
+    format!("{}_{}", table, feed_id)
+}
 
-    let feed_id: i32 = transaction
-        .query("insert into feed default values returning feed_id", &[])?
-        .first()
-        .unwrap()
-        .get(0);
+/// Imports the feed in a directory (or the part of it in the subset), unless
+/// a feed with the same content has already been imported and `force` is
+/// not set.
+///
+/// The tables are loaded in parallel into new tables, which are then
+/// attached as the feed's partitions in one transaction, so that either
+/// the whole feed is imported or nothing is.
+async fn import(
+    path: &Path,
+    metadata: &FeedMetadata,
+    options: &ImportOptions,
+    client: &mut Client,
+) -> Result<(), ImporterError> {
+    let paths = TABLE_AND_FILE_NAMES
+        .iter()
+        .map(|s| path.join(s.0))
+        .collect::<Vec<_>>();
+    let mut content_hash = tokio::task::spawn_blocking(move || utils::hash_files(&paths)).await??;
+    // a subset is not identical to the whole feed, or to other subsets
+    if !options.subset.is_empty() {
+        content_hash = format!("{} {}", content_hash, options.subset);
+    }
+    if !options.force {
+        let existing = client
+            .query_opt(
+                "select feed_id from feed where content_hash = $1 order by feed_id desc limit 1",
+                &[&content_hash],
+            )
+            .await?;
+        if let Some(row) = existing {
+            let feed_id: i32 = row.get(0);
+            // so that the next download from the same source is conditional
+            client.execute(
+                "update feed set etag = $2, last_modified = $3 where feed_id = $1 and source = $4",
+                &[
+                    &feed_id,
+                    &metadata.etag,
+                    &metadata.last_modified,
+                    &metadata.source,
+                ],
+            )
+            .await?;
+            println!(
+                "Feed is identical to feed {}, not importing it (use --force to import anyway)",
+                feed_id
+            );
+            return Ok(());
+        }
+    }
 
-    let bar = utils::progress_bar(
-        TABLE_AND_FILE_NAMES.len() as u64,
-        "Importing {spinner} [{elapsed_precise}] [{bar:60.yellow}] {pos}/{len}",
-    );
+    println!("Importing data");
 
-    // TODO support optional tables
-    for s in &TABLE_AND_FILE_NAMES {
-        transaction.execute(
-            &format!(
-                "alter table {} alter column feed_id set default {}",
-                s.1, feed_id
-            )[..],
+    // taken now, as the feed row is only inserted with the loaded tables
+    let feed_id: i32 = client
+        .query_one(
+            "select nextval(pg_get_serial_sequence('feed', 'feed_id'))::integer",
             &[],
-        )?;
-
-        let file_path = path.join(&s.0);
-        bar.println(format!("Reading from {}", &file_path.display()));
-
-        let file_content = std::fs::read_to_string(&file_path)?;
-
-        // header is the csv header, we put this in the copy command
-        // so that the csv data is input correctly.
-        let mut header = match file_content.find(|c| c == '\n') {
-            Some(i) => &file_content[..i],
-            None => return Err(ImporterError::NoDataInFile(file_path.display().to_string())),
-        };
-
-        if header.ends_with('\r') {
-            header = &header[0..header.len() - 1];
-        }
-        // strip BOM (ef bb bf) if present
-        if header.bytes().nth(0) == Some(239u8)
-            && header.bytes().nth(1) == Some(187u8)
-            && header.bytes().nth(2) == Some(191u8)
-        {
-            header = &header[3..header.len()];
-        }
+        )
+        .await?
+        .get(0);
 
-        let command = format!(
-            "copy {}({}) from stdin delimiter ',' csv header;",
-            s.1, header
-        );
-        bar.println(format!("Running: {}", &command));
-
-        let mut writer = transaction.copy_in(&command[..])?;
-
-        bar.println("Writing data");
-
-        if s.1 == "stop_time" {
-            let mut buffer = BytesMut::new().writer();
-            {
-                let mut reader = csv::Reader::from_reader(file_content.as_bytes());
-                let mut csv_writer = csv::Writer::from_writer(&mut buffer);
-                let header_columns = header.split(',').collect::<Vec<_>>();
-                let arrival_time_idx = header_columns.iter().position(|r| *r == "arrival_time");
-                let departure_time_idx = header_columns.iter().position(|r| *r == "departure_time");
-
-                for row in reader.records() {
-                    let record = row?; // todo, ugly code!
-                    let new_record = record
-                        .iter()
-                        .enumerate()
-                        .map(|(i, content)| {
-                            if Some(i) == arrival_time_idx || Some(i) == departure_time_idx {
-                                let x = content
-                                    .split(':')
-                                    .map(|x| x.parse::<i32>().unwrap())
-                                    .collect::<Vec<i32>>();
-                                (x[0] * 3600 + x[1] * 60 + x[2]).to_string()
-                            } else {
-                                content.to_string()
-                            }
-                        })
-                        .collect::<Vec<String>>();
-                    csv_writer.write_record(new_record)?;
-                }
-            }
-            writer.write_all(&buffer.into_inner())?;
-        } else {
-            writer.write_all(file_content.as_bytes())?;
+    let mut result = staging::load_tables(path, feed_id, &options.db_url, options.jobs).await;
+    if result.is_ok() {
+        result = attach_tables(feed_id, &content_hash, metadata, options, client).await;
+    }
+    if result.is_err() {
+        if let Err(e) = staging::drop_tables(client, feed_id).await {
+            eprintln!(
+                "Could not drop the tables loaded for feed {}: {}",
+                feed_id, e
+            );
         }
-        bar.println("Committing to database");
-
-        writer.finish()?;
+    }
+    result
+}
 
-        transaction.execute(
-            &format!("alter table {} alter column feed_id drop default", s.1)[..],
-            &[],
-        )?;
-        bar.inc(1);
+/// Adds a feed with its loaded tables as the partitions of the feed tables.
+/// Constraints, including foreign keys, are checked as they are attached.
+async fn attach_tables(
+    feed_id: i32,
+    content_hash: &str,
+    metadata: &FeedMetadata,
+    options: &ImportOptions,
+    client: &mut Client,
+) -> Result<(), ImporterError> {
+    let transaction = client.transaction().await?;
+    transaction
+        .execute(
+            "insert into feed (feed_id, source, etag, last_modified, content_hash) \
+         values ($1, $2, $3, $4, $5)",
+            &[
+                &feed_id,
+                &metadata.source,
+                &metadata.etag,
+                &metadata.last_modified,
+                &content_hash,
+            ],
+        )
+        .await?;
+
+    options
+        .subset
+        .apply(&transaction, |table| partition_name(table, feed_id))
+        .await?;
+    service_dates::create_table(&transaction, feed_id).await?;
+
+    let tables = TABLE_AND_FILE_NAMES
+        .iter()
+        .map(|s| &s.1)
+        .chain(DERIVED_TABLES.iter());
+    for table in tables {
+        println!("Attaching {}", table);
+        transaction
+            .batch_execute(
+                &format!(
+                    "alter table {0} attach partition {1} for values in ({2});",
+                    table,
+                    partition_name(table, feed_id),
+                    feed_id
+                )[..],
+            )
+            .await?;
     }
-    bar.finish_and_clear();
-    transaction.commit()?;
+    transaction.commit().await?;
     Ok(())
 }
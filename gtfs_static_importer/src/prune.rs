@@ -0,0 +1,128 @@
+use chrono::{Duration, NaiveDate};
+
+use std::collections::HashMap;
+use std::num::NonZeroU32;
+
+use crate::feeds::FeedSummary;
+
+/// Which feeds to delete, with the reason for each, in order of feed_id.
+///
+/// A feed is deleted if its service ended more than `expired_days` days
+/// before `today`, or if for each of its agencies there are `keep_latest`
+/// newer feeds (by feed_id) with that agency. `keep_latest` never deletes
+/// the latest feed of an agency, but `expired_days` does if it has expired.
+pub fn feeds_to_prune(
+    feeds: &[FeedSummary],
+    today: NaiveDate,
+    expired_days: Option<u32>,
+    keep_latest: Option<NonZeroU32>,
+) -> Vec<(i32, String)> {
+    let mut pruned = Vec::new();
+
+    // number of newer feeds with each agency
+    let mut newer_feeds: HashMap<&str, u32> = HashMap::new();
+    let mut sorted = feeds.iter().collect::<Vec<_>>();
+    sorted.sort_unstable_by_key(|f| std::cmp::Reverse(f.feed_id));
+
+    for feed in sorted {
+        let mut reasons = Vec::new();
+
+        if let (Some(days), Some(end_date)) = (expired_days, feed.end_date) {
+            if end_date < today - Duration::days(days.into()) {
+                reasons.push(format!("service ended on {}", end_date));
+            }
+        }
+        if let Some(keep) = keep_latest.map(NonZeroU32::get) {
+            let superseded = !feed.agencies.is_empty()
+                && feed
+                    .agencies
+                    .iter()
+                    .all(|a| newer_feeds.get(&a[..]).copied().unwrap_or(0) >= keep);
+            if superseded {
+                reasons.push(format!("{} newer feeds for its agencies", keep));
+            }
+        }
+        for agency in &feed.agencies {
+            *newer_feeds.entry(&agency[..]).or_default() += 1;
+        }
+
+        if !reasons.is_empty() {
+            pruned.push((feed.feed_id, reasons.join(", ")));
+        }
+    }
+    pruned.reverse();
+    pruned
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feed(feed_id: i32, agencies: &[&str], end_date: Option<NaiveDate>) -> FeedSummary {
+        FeedSummary {
+            feed_id,
+            agencies: agencies.iter().map(|a| a.to_string()).collect(),
+            start_date: None,
+            end_date,
+            source: None,
+            imported_at: chrono::Utc::now(),
+            etag: None,
+            last_modified: None,
+            content_hash: None,
+        }
+    }
+    fn date(d: u32) -> Option<NaiveDate> {
+        NaiveDate::from_ymd_opt(2020, 1, d)
+    }
+    fn ids(pruned: Vec<(i32, String)>) -> Vec<i32> {
+        pruned.into_iter().map(|p| p.0).collect()
+    }
+
+    #[test]
+    fn expired() {
+        let feeds = vec![
+            feed(1, &["A"], date(1)),
+            feed(2, &["A"], date(10)),
+            feed(3, &["A"], None),
+        ];
+        let today = date(20).unwrap();
+        assert_eq!(
+            ids(feeds_to_prune(&feeds, today, Some(5), None)),
+            vec![1, 2]
+        );
+        assert_eq!(ids(feeds_to_prune(&feeds, today, Some(15), None)), vec![1]);
+        assert_eq!(
+            ids(feeds_to_prune(&feeds, today, None, None)),
+            Vec::<i32>::new()
+        );
+    }
+
+    #[test]
+    fn keep_latest() {
+        let feeds = vec![
+            feed(1, &["A"], None),
+            feed(2, &["A", "B"], None),
+            feed(3, &["B"], None),
+            feed(4, &["A"], None),
+            feed(5, &[], None),
+        ];
+        let today = date(1).unwrap();
+        let keep = |n| NonZeroU32::new(n);
+        assert_eq!(
+            ids(feeds_to_prune(&feeds, today, None, keep(1))),
+            vec![1, 2]
+        );
+        assert_eq!(ids(feeds_to_prune(&feeds, today, None, keep(2))), vec![1]);
+        assert_eq!(
+            ids(feeds_to_prune(&feeds, today, None, keep(3))),
+            Vec::<i32>::new()
+        );
+
+        // expired_days deletes the latest feed of an agency
+        let feeds = vec![feed(1, &["A"], date(1)), feed(2, &["A"], date(2))];
+        assert_eq!(
+            ids(feeds_to_prune(&feeds, date(20).unwrap(), Some(5), keep(1))),
+            vec![1, 2]
+        );
+    }
+}
@@ -0,0 +1,48 @@
+use tokio_postgres::Transaction;
+
+use crate::{partition_name, ImporterError};
+
+/// Creates the service_date table of a feed, the days on which each of its
+/// services runs, from its (not yet attached) calendar and calendar_date
+/// tables.
+pub async fn create_table(
+    transaction: &Transaction<'_>,
+    feed_id: i32,
+) -> Result<(), ImporterError> {
+    let table = partition_name("service_date", feed_id);
+    let calendar = partition_name("calendar", feed_id);
+    let calendar_date = partition_name("calendar_date", feed_id);
+    transaction
+        .batch_execute(
+            &format!(
+                "create table {table} (like service_date including defaults);
+                alter table {table} add check (feed_id = {feed_id});
+                insert into {table} (feed_id, service_id, date)
+                select {feed_id}, c.service_id, d::date from {calendar} c
+                    cross join generate_series(c.start_date, c.end_date, '1 day'::interval) d
+                    where case extract(isodow from d)
+                            when 1 then c.monday
+                            when 2 then c.tuesday
+                            when 3 then c.wednesday
+                            when 4 then c.thursday
+                            when 5 then c.friday
+                            when 6 then c.saturday
+                            else c.sunday
+                        end
+                    and not exists (
+                        select 1 from {calendar_date} cd
+                        where cd.service_id = c.service_id and cd.date = d::date
+                            and cd.exception_type = 2
+                    )
+                union
+                select {feed_id}, service_id, date from {calendar_date}
+                    where exception_type = 1;",
+                table = table,
+                feed_id = feed_id,
+                calendar = calendar,
+                calendar_date = calendar_date
+            )[..],
+        )
+        .await?;
+    Ok(())
+}
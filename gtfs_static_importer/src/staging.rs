@@ -0,0 +1,174 @@
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use tokio_postgres::Client;
+
+use std::path::Path;
+
+use crate::{columns, partition_name, stop_times, utils};
+use crate::{ImporterError, DERIVED_TABLES, TABLE_AND_FILE_NAMES};
+
+/// Loads each file of a feed into a new table named like the partition it
+/// will become, on up to `jobs` connections at once.
+///
+/// The tables have no foreign keys, so they can be loaded in any order;
+/// they are checked when attached. Each table is committed on its own, so
+/// they must be dropped with `drop_tables` if the import fails.
+pub async fn load_tables(
+    path: &Path,
+    feed_id: i32,
+    db_url: &str,
+    jobs: usize,
+) -> Result<(), ImporterError> {
+    // the largest files (usually stop_times.txt) are loaded first, as
+    // they take the longest.
+    let mut tables = TABLE_AND_FILE_NAMES
+        .iter()
+        .map(|s| {
+            let len = std::fs::metadata(path.join(s.0)).map_or(0, |m| m.len());
+            (len, *s)
+        })
+        .collect::<Vec<_>>();
+    tables.sort_by_key(|(len, _)| std::cmp::Reverse(*len));
+
+    let bar = utils::progress_bar(
+        TABLE_AND_FILE_NAMES.len() as u64,
+        "Importing {spinner} [{elapsed_precise}] [{bar:60.yellow}] {pos}/{len}",
+    );
+
+    // each table is loaded in its own task, so that converting one file
+    // does not hold up the others.
+    let results = futures::stream::iter(tables)
+        .map(|(_, (file_name, table))| {
+            let (bar, path, db_url) = (bar.clone(), path.to_path_buf(), db_url.to_string());
+            tokio::spawn(async move {
+                let mut client = utils::connect(&db_url).await?;
+                load_table(&mut client, &path, file_name, table, feed_id, &bar).await?;
+                bar.inc(1);
+                Ok::<_, ImporterError>(())
+            })
+        })
+        .buffer_unordered(jobs.max(1))
+        .collect::<Vec<_>>()
+        .await;
+    bar.finish_and_clear();
+
+    for result in results {
+        result??;
+    }
+    Ok(())
+}
+
+async fn load_table(
+    client: &mut Client,
+    path: &Path,
+    file_name: &str,
+    table: &str,
+    feed_id: i32,
+    bar: &indicatif::ProgressBar,
+) -> Result<(), ImporterError> {
+    let transaction = client.transaction().await?;
+
+    // the check lets postgres skip scanning the table for rows outside the
+    // partition when attaching.
+    let partition = partition_name(table, feed_id);
+    transaction
+        .batch_execute(
+            &format!(
+                "create table {0} (like {1} including defaults);
+            alter table {0} alter column feed_id set default {2};
+            alter table {0} add check (feed_id = {2});",
+                partition, table, feed_id
+            )[..],
+        )
+        .await?;
+
+    let file_path = path.join(file_name);
+    bar.println(format!("Reading from {}", &file_path.display()));
+
+    let file_content = tokio::fs::read_to_string(&file_path).await?;
+
+    if !file_content.contains('\n') {
+        return Err(ImporterError::NoDataInFile(file_path.display().to_string()));
+    }
+    let header = csv::Reader::from_reader(file_content.as_bytes())
+        .headers()?
+        .iter()
+        .map(|h| h.trim().to_string())
+        .collect::<Vec<_>>();
+
+    // only copy the columns that the table has, other columns
+    // (extensions or newer additions to the spec) are dropped.
+    let table_columns = columns::table_columns(&transaction, table).await?;
+    let (known, unknown): (Vec<_>, Vec<_>) =
+        (0..header.len()).partition(|&i| table_columns.contains(&header[i]));
+    if !unknown.is_empty() {
+        bar.println(format!(
+            "Ignoring unknown columns in {}: {}",
+            file_name,
+            unknown
+                .iter()
+                .map(|&i| &header[i][..])
+                .collect::<Vec<_>>()
+                .join(", ")
+        ));
+    }
+
+    let command = format!(
+        "copy {}({}) from stdin delimiter ',' csv header;",
+        partition,
+        known
+            .iter()
+            .map(|&i| &header[i][..])
+            .collect::<Vec<_>>()
+            .join(",")
+    );
+    bar.println(format!("Running: {}", &command));
+
+    let data = if table == "stop_time" {
+        let stops_content = tokio::fs::read_to_string(path.join("stops.txt")).await?;
+        let file_name = file_path.display().to_string();
+        tokio::task::spawn_blocking(move || {
+            let stop_positions = stop_times::read_stop_positions(&stops_content)?;
+            stop_times::convert(&file_name, &file_content, &stop_positions)
+        })
+        .await??
+    } else {
+        file_content.into_bytes()
+    };
+    let data = if unknown.is_empty() {
+        data
+    } else {
+        columns::select_columns(&data, &known)?
+    };
+
+    let sink = transaction.copy_in(&command[..]).await?;
+    futures::pin_mut!(sink);
+    sink.send(Bytes::from(data)).await?;
+    sink.finish().await?;
+
+    transaction
+        .batch_execute(
+            &format!(
+                "alter table {} alter column feed_id drop default",
+                partition
+            )[..],
+        )
+        .await?;
+    transaction.commit().await?;
+    bar.println(format!("Loaded {}", file_name));
+    Ok(())
+}
+
+/// Drops the tables loaded for a feed which failed to import.
+pub async fn drop_tables(client: &Client, feed_id: i32) -> Result<(), ImporterError> {
+    let tables = TABLE_AND_FILE_NAMES
+        .iter()
+        .map(|s| &s.1)
+        .chain(DERIVED_TABLES.iter());
+    for table in tables {
+        client
+            .batch_execute(&format!("drop table if exists {}", partition_name(table, feed_id))[..])
+            .await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,398 @@
+use std::collections::HashMap;
+
+use crate::ImporterError;
+
+/// Parses a GTFS time (`HH:MM:SS`, or `H:MM:SS` for hours below 10) into
+/// seconds after midnight of the service day. Hours may exceed 23 for trips
+/// running past midnight. Returns `None` for a blank field.
+pub fn parse_time(s: &str) -> Result<Option<i32>, String> {
+    let s = s.trim();
+    if s.is_empty() {
+        return Ok(None);
+    }
+    let invalid = || format!("invalid time \"{}\", expected HH:MM:SS", s);
+
+    let parts = s.split(':').collect::<Vec<_>>();
+    if parts.len() != 3 {
+        return Err(invalid());
+    }
+    let is_digits = |p: &str| !p.is_empty() && p.bytes().all(|b| b.is_ascii_digit());
+    if !parts.iter().all(|p| is_digits(p)) || parts[1].len() != 2 || parts[2].len() != 2 {
+        return Err(invalid());
+    }
+
+    let hours = parts[0].parse::<i32>().map_err(|_| invalid())?;
+    let minutes = parts[1].parse::<i32>().map_err(|_| invalid())?;
+    let seconds = parts[2].parse::<i32>().map_err(|_| invalid())?;
+    if minutes >= 60 || seconds >= 60 {
+        return Err(invalid());
+    }
+    hours
+        .checked_mul(3600)
+        .and_then(|h| h.checked_add(minutes * 60 + seconds))
+        .map(Some)
+        .ok_or_else(invalid)
+}
+
+/// Formats seconds after midnight as a GTFS time, the inverse of
+/// `parse_time`. Hours are not wrapped, so times after midnight of the
+/// service day are 24:00:00 or later.
+pub fn format_time(seconds: i32) -> String {
+    format!(
+        "{:02}:{:02}:{:02}",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60
+    )
+}
+
+/// One row of a trip, holding what is needed to fill in missing times.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StopTimeRow {
+    /// Line in the csv file, for error messages.
+    pub line: u64,
+    pub arrival_time: Option<i32>,
+    pub departure_time: Option<i32>,
+    pub shape_dist_traveled: Option<f64>,
+    /// (lat, lon) of the stop, if known.
+    pub stop_position: Option<(f64, f64)>,
+}
+
+/// Fills blank times in a trip's rows, which must be sorted by stop_sequence.
+///
+/// A time is copied from the other column if only one of arrival/departure
+/// is given. Otherwise times are interpolated between the surrounding
+/// timepoints, proportionally to `shape_dist_traveled` if every row in the
+/// gap has it, else to the straight line distance between stops, else
+/// evenly by stop count.
+pub fn interpolate_trip(rows: &mut [StopTimeRow]) -> Result<(), (u64, String)> {
+    for row in rows.iter_mut() {
+        if row.arrival_time.is_none() {
+            row.arrival_time = row.departure_time;
+        }
+        if row.departure_time.is_none() {
+            row.departure_time = row.arrival_time;
+        }
+    }
+
+    if let Some(row) = rows.first().filter(|r| r.departure_time.is_none()) {
+        return Err((row.line, "first stop of a trip must have a time".into()));
+    }
+    if let Some(row) = rows.last().filter(|r| r.arrival_time.is_none()) {
+        return Err((row.line, "last stop of a trip must have a time".into()));
+    }
+
+    let mut start = 0;
+    while start + 1 < rows.len() {
+        let end = start
+            + 1
+            + rows[start + 1..]
+                .iter()
+                .position(|r| r.arrival_time.is_some())
+                .expect("last row has a time");
+
+        if end > start + 1 {
+            let distances = gap_distances(&rows[start..=end]);
+            let total = distances[distances.len() - 1];
+            let from = rows[start].departure_time.unwrap();
+            let to = rows[end].arrival_time.unwrap();
+            if to < from {
+                return Err((
+                    rows[end].line,
+                    "time is earlier than the previous timepoint".into(),
+                ));
+            }
+
+            for (i, row) in rows[start + 1..end].iter_mut().enumerate() {
+                let fraction = if total > 0.0 {
+                    distances[i + 1] / total
+                } else {
+                    (i + 1) as f64 / (end - start) as f64
+                };
+                let time = from + (fraction * f64::from(to - from)).round() as i32;
+                row.arrival_time = Some(time);
+                row.departure_time = Some(time);
+            }
+        }
+        start = end;
+    }
+    Ok(())
+}
+
+/// Cumulative distance along a gap between two timepoints, starting at 0.
+fn gap_distances(rows: &[StopTimeRow]) -> Vec<f64> {
+    if let Some(d) = rows
+        .iter()
+        .map(|r| r.shape_dist_traveled)
+        .collect::<Option<Vec<_>>>()
+    {
+        return d.iter().map(|x| x - d[0]).collect();
+    }
+    if let Some(p) = rows
+        .iter()
+        .map(|r| r.stop_position)
+        .collect::<Option<Vec<_>>>()
+    {
+        let mut total = 0.0;
+        let mut d = vec![0.0];
+        for w in p.windows(2) {
+            total += haversine_distance(w[0], w[1]);
+            d.push(total);
+        }
+        return d;
+    }
+    (0..rows.len()).map(|i| i as f64).collect()
+}
+
+/// Great circle distance in metres between two (lat, lon) points.
+pub fn haversine_distance(a: (f64, f64), b: (f64, f64)) -> f64 {
+    const EARTH_RADIUS: f64 = 6_371_000.0;
+    let (lat1, lat2) = (a.0.to_radians(), b.0.to_radians());
+    let d_lat = lat2 - lat1;
+    let d_lon = (b.1 - a.1).to_radians();
+    let h = (d_lat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (d_lon / 2.0).sin().powi(2);
+    2.0 * EARTH_RADIUS * h.sqrt().asin()
+}
+
+/// Reads `stop_id -> (lat, lon)` from stops.txt, used when interpolating times.
+pub fn read_stop_positions(content: &str) -> Result<HashMap<String, (f64, f64)>, ImporterError> {
+    let mut reader = csv::Reader::from_reader(content.as_bytes());
+    let headers = reader.headers()?.clone();
+    let column = |name| headers.iter().position(|h| h.trim() == name);
+
+    let mut positions = HashMap::new();
+    if let (Some(id), Some(lat), Some(lon)) =
+        (column("stop_id"), column("stop_lat"), column("stop_lon"))
+    {
+        for record in reader.records() {
+            let record = record?;
+            let lat = record.get(lat).and_then(|s| s.trim().parse().ok());
+            let lon = record.get(lon).and_then(|s| s.trim().parse().ok());
+            if let (Some(stop_id), Some(lat), Some(lon)) = (record.get(id), lat, lon) {
+                positions.insert(stop_id.to_string(), (lat, lon));
+            }
+        }
+    }
+    Ok(positions)
+}
+
+/// Rewrites stop_times.txt for `copy`: times become seconds after midnight,
+/// and blank times are interpolated. The output keeps the input's header.
+pub fn convert(
+    file_name: &str,
+    content: &str,
+    stop_positions: &HashMap<String, (f64, f64)>,
+) -> Result<Vec<u8>, ImporterError> {
+    let invalid = |line: u64, reason: String| {
+        ImporterError::InvalidStopTime(file_name.to_string(), line, reason)
+    };
+
+    let mut reader = csv::Reader::from_reader(content.as_bytes());
+    let headers = reader.headers()?.clone();
+    let column = |name: &str| {
+        headers
+            .iter()
+            .position(|h| h.trim() == name)
+            .ok_or_else(|| invalid(1, format!("missing column {}", name)))
+    };
+    let trip_id_idx = column("trip_id")?;
+    let stop_id_idx = column("stop_id")?;
+    let stop_sequence_idx = column("stop_sequence")?;
+    let arrival_time_idx = column("arrival_time")?;
+    let departure_time_idx = column("departure_time")?;
+    let shape_dist_traveled_idx = column("shape_dist_traveled").ok();
+
+    let mut records = Vec::new();
+    let mut rows = Vec::new();
+    // record indices of each trip, with their stop_sequence
+    let mut trips: HashMap<String, Vec<(i32, usize)>> = HashMap::new();
+
+    for record in reader.records() {
+        let record = record?;
+        let line = record.position().map_or(0, |p| p.line());
+        let field = |i: usize| record.get(i).unwrap_or("").trim();
+
+        let stop_sequence = field(stop_sequence_idx).parse::<i32>().map_err(|_| {
+            invalid(
+                line,
+                format!("invalid stop_sequence \"{}\"", field(stop_sequence_idx)),
+            )
+        })?;
+        let arrival_time = parse_time(field(arrival_time_idx)).map_err(|e| invalid(line, e))?;
+        let departure_time = parse_time(field(departure_time_idx)).map_err(|e| invalid(line, e))?;
+        let shape_dist_traveled = shape_dist_traveled_idx.and_then(|i| field(i).parse().ok());
+
+        trips
+            .entry(field(trip_id_idx).to_string())
+            .or_default()
+            .push((stop_sequence, records.len()));
+        rows.push(StopTimeRow {
+            line,
+            arrival_time,
+            departure_time,
+            shape_dist_traveled,
+            stop_position: stop_positions.get(field(stop_id_idx)).copied(),
+        });
+        records.push(record);
+    }
+
+    for (_, mut indices) in trips {
+        indices.sort_unstable();
+        let mut trip_rows = indices
+            .iter()
+            .map(|&(_, i)| rows[i].clone())
+            .collect::<Vec<_>>();
+        interpolate_trip(&mut trip_rows).map_err(|(line, e)| invalid(line, e))?;
+        for ((_, i), row) in indices.into_iter().zip(trip_rows) {
+            rows[i] = row;
+        }
+    }
+
+    let mut csv_writer = csv::Writer::from_writer(Vec::new());
+    csv_writer.write_record(&headers)?;
+    for (record, row) in records.iter().zip(rows) {
+        let new_record = record.iter().enumerate().map(|(i, content)| {
+            if i == arrival_time_idx {
+                row.arrival_time.unwrap().to_string()
+            } else if i == departure_time_idx {
+                row.departure_time.unwrap().to_string()
+            } else {
+                content.to_string()
+            }
+        });
+        csv_writer.write_record(new_record)?;
+    }
+    csv_writer
+        .into_inner()
+        .map_err(|e| ImporterError::FileError(e.into_error()))
+}
+
+/// Rewrites stop_time rows copied out of the database (with a header) as
+/// stop_times.txt: times in seconds become `HH:MM:SS`.
+pub fn format_times(content: &[u8]) -> Result<Vec<u8>, ImporterError> {
+    let mut reader = csv::Reader::from_reader(content);
+    let headers = reader.headers()?.clone();
+    let time_columns = headers
+        .iter()
+        .map(|h| h == "arrival_time" || h == "departure_time")
+        .collect::<Vec<_>>();
+
+    let mut csv_writer = csv::Writer::from_writer(Vec::new());
+    csv_writer.write_record(&headers)?;
+    for record in reader.records() {
+        let record = record?;
+        let new_record = record
+            .iter()
+            .zip(&time_columns)
+            .map(|(content, &is_time)| match content.parse() {
+                Ok(seconds) if is_time => format_time(seconds),
+                _ => content.to_string(),
+            });
+        csv_writer.write_record(new_record)?;
+    }
+    csv_writer
+        .into_inner()
+        .map_err(|e| ImporterError::FileError(e.into_error()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(time: Option<i32>, dist: Option<f64>) -> StopTimeRow {
+        StopTimeRow {
+            line: 0,
+            arrival_time: time,
+            departure_time: time,
+            shape_dist_traveled: dist,
+            stop_position: None,
+        }
+    }
+    fn times(rows: &[StopTimeRow]) -> Vec<Option<i32>> {
+        rows.iter().map(|r| r.departure_time).collect()
+    }
+
+    #[test]
+    fn parse_times() {
+        assert_eq!(parse_time("08:05:09"), Ok(Some(8 * 3600 + 5 * 60 + 9)));
+        assert_eq!(parse_time("8:05:09"), Ok(Some(8 * 3600 + 5 * 60 + 9)));
+        assert_eq!(parse_time(" 25:00:00 "), Ok(Some(25 * 3600)));
+        assert_eq!(parse_time(""), Ok(None));
+        assert_eq!(parse_time("  "), Ok(None));
+        assert!(parse_time("08:5:09").is_err());
+        assert!(parse_time("08:60:00").is_err());
+        assert!(parse_time("08:00").is_err());
+        assert!(parse_time("-1:00:00").is_err());
+        assert!(parse_time("ab:cd:ef").is_err());
+    }
+
+    #[test]
+    fn format_times() {
+        assert_eq!(format_time(8 * 3600 + 5 * 60 + 9), "08:05:09");
+        assert_eq!(format_time(0), "00:00:00");
+        assert_eq!(format_time(25 * 3600 + 30), "25:00:30");
+        assert_eq!(
+            parse_time(&format_time(100 * 3600 + 59)),
+            Ok(Some(100 * 3600 + 59))
+        );
+
+        let content = "trip_id,arrival_time,departure_time,stop_id,stop_sequence\n\
+                       t,86400,86460,s,1\n";
+        assert_eq!(
+            String::from_utf8(super::format_times(content.as_bytes()).unwrap()).unwrap(),
+            "trip_id,arrival_time,departure_time,stop_id,stop_sequence\n\
+             t,24:00:00,24:01:00,s,1\n"
+        );
+    }
+
+    #[test]
+    fn interpolate_by_shape_distance() {
+        let mut rows = vec![
+            row(Some(0), Some(0.0)),
+            row(None, Some(100.0)),
+            row(None, Some(400.0)),
+            row(Some(1000), Some(1000.0)),
+        ];
+        interpolate_trip(&mut rows).unwrap();
+        assert_eq!(
+            times(&rows),
+            vec![Some(0), Some(100), Some(400), Some(1000)]
+        );
+    }
+
+    #[test]
+    fn interpolate_by_stop_position() {
+        let mut rows = vec![row(Some(0), None), row(None, None), row(Some(600), None)];
+        rows[0].stop_position = Some((0.0, 0.0));
+        rows[1].stop_position = Some((0.0, 0.01));
+        rows[2].stop_position = Some((0.0, 0.03));
+        interpolate_trip(&mut rows).unwrap();
+        assert_eq!(times(&rows), vec![Some(0), Some(200), Some(600)]);
+    }
+
+    #[test]
+    fn interpolate_evenly() {
+        let mut rows = vec![
+            row(Some(0), None),
+            row(None, None),
+            row(None, None),
+            row(Some(90), None),
+        ];
+        interpolate_trip(&mut rows).unwrap();
+        assert_eq!(times(&rows), vec![Some(0), Some(30), Some(60), Some(90)]);
+    }
+
+    #[test]
+    fn copies_single_time() {
+        let mut rows = vec![row(Some(0), None), row(Some(60), None)];
+        rows[1].departure_time = None;
+        interpolate_trip(&mut rows).unwrap();
+        assert_eq!(rows[1].departure_time, Some(60));
+    }
+
+    #[test]
+    fn missing_end_times() {
+        assert!(interpolate_trip(&mut [row(None, None), row(Some(60), None)]).is_err());
+        assert!(interpolate_trip(&mut [row(Some(0), None), row(None, None)]).is_err());
+    }
+}
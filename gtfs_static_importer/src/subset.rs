@@ -0,0 +1,199 @@
+use structopt::StructOpt;
+use tokio_postgres::Transaction;
+
+use std::fmt;
+use std::str::FromStr;
+
+use crate::ImporterError;
+
+/// Filters to import only part of a feed. Trips matching every filter are
+/// kept whole, with everything they need: their stop_times, stops (and the
+/// stops' parent stations), routes, agencies, shapes and calendars.
+#[derive(StructOpt, Debug, Default)]
+pub struct Subset {
+    /// Keep trips which stop inside "min_lon,min_lat,max_lon,max_lat"
+    #[structopt(long)]
+    pub bbox: Option<BoundingBox>,
+    /// Keep trips of routes run by this agency_id (can be repeated)
+    #[structopt(long = "agency")]
+    pub agencies: Vec<String>,
+    /// Keep trips of this route_id (can be repeated)
+    #[structopt(long = "route")]
+    pub routes: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BoundingBox {
+    pub min_lon: f64,
+    pub min_lat: f64,
+    pub max_lon: f64,
+    pub max_lat: f64,
+}
+
+impl FromStr for BoundingBox {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            format!(
+                "invalid bbox \"{}\", expected min_lon,min_lat,max_lon,max_lat",
+                s
+            )
+        };
+        let values = s
+            .split(',')
+            .map(|v| v.trim().parse::<f64>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|_| invalid())?;
+        match values[..] {
+            [min_lon, min_lat, max_lon, max_lat] if min_lon <= max_lon && min_lat <= max_lat => {
+                Ok(Self {
+                    min_lon,
+                    min_lat,
+                    max_lon,
+                    max_lat,
+                })
+            }
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl fmt::Display for Subset {
+    /// Describes the filters, to tell apart subsets of the same feed.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut parts = Vec::new();
+        if let Some(b) = &self.bbox {
+            parts.push(format!(
+                "bbox={},{},{},{}",
+                b.min_lon, b.min_lat, b.max_lon, b.max_lat
+            ));
+        }
+        if !self.agencies.is_empty() {
+            parts.push(format!("agency={}", self.agencies.join(",")));
+        }
+        if !self.routes.is_empty() {
+            parts.push(format!("route={}", self.routes.join(",")));
+        }
+        write!(f, "{}", parts.join(";"))
+    }
+}
+
+impl Subset {
+    pub fn is_empty(&self) -> bool {
+        self.bbox.is_none() && self.agencies.is_empty() && self.routes.is_empty()
+    }
+
+    /// Deletes what is not in the subset from a feed's tables, which are
+    /// named by `table_name`. The tables must not have foreign keys yet.
+    pub async fn apply(
+        &self,
+        transaction: &Transaction<'_>,
+        table_name: impl Fn(&str) -> String,
+    ) -> Result<(), ImporterError> {
+        if self.is_empty() {
+            return Ok(());
+        }
+        let (trip, stop_time, stop, route, agency, shape, calendar, calendar_date) = (
+            table_name("trip"),
+            table_name("stop_time"),
+            table_name("stop"),
+            table_name("route"),
+            table_name("agency"),
+            table_name("shape"),
+            table_name("calendar"),
+            table_name("calendar_date"),
+        );
+
+        transaction
+            .batch_execute(
+                "create temporary table kept_trip (trip_id text primary key) on commit drop",
+            )
+            .await?;
+        let bbox = self
+            .bbox
+            .map(|b| vec![b.min_lon, b.min_lat, b.max_lon, b.max_lat]);
+        let kept = transaction
+            .execute(
+                &format!(
+                    "insert into kept_trip
+                select t.trip_id from {trip} t join {route} r on r.route_id = t.route_id
+                where (cardinality($1::text[]) = 0 or r.agency_id = any($1))
+                    and (cardinality($2::text[]) = 0 or r.route_id = any($2))
+                    and ($3::float8[] is null or exists (
+                        select 1 from {stop_time} st join {stop} s on s.stop_id = st.stop_id
+                        where st.trip_id = t.trip_id
+                            and s.stop_lon between $3[1] and $3[3]
+                            and s.stop_lat between $3[2] and $3[4]))",
+                    trip = trip,
+                    route = route,
+                    stop_time = stop_time,
+                    stop = stop
+                )[..],
+                &[&self.agencies, &self.routes, &bbox],
+            )
+            .await?;
+        if kept == 0 {
+            return Err(ImporterError::EmptySubset(self.to_string()));
+        }
+        println!("Keeping {} trips matching {}", kept, self);
+
+        // in order, as each table is filtered by what is left in the ones before
+        transaction
+            .batch_execute(&format!(
+                "delete from {trip} where trip_id not in (select trip_id from kept_trip);
+            delete from {stop_time} where trip_id not in (select trip_id from kept_trip);
+            delete from {route} where route_id not in (select route_id from {trip});
+            delete from {agency} where agency_id not in (select agency_id from {route});
+            delete from {shape} where shape_id not in
+                (select shape_id from {trip} where shape_id is not null);
+            delete from {calendar} where service_id not in (select service_id from {trip});
+            delete from {calendar_date} where service_id not in (select service_id from {trip});
+            with recursive needed(stop_id) as (
+                select stop_id from {stop_time}
+                union
+                select s.parent_station from {stop} s join needed n on s.stop_id = n.stop_id
+                where s.parent_station is not null
+            )
+            delete from {stop} where stop_id not in (select stop_id from needed);",
+                trip = trip,
+                stop_time = stop_time,
+                stop = stop,
+                route = route,
+                agency = agency,
+                shape = shape,
+                calendar = calendar,
+                calendar_date = calendar_date
+            ))
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_bbox() {
+        assert_eq!(
+            "174.7,-36.9,174.8,-36.8".parse(),
+            Ok(BoundingBox {
+                min_lon: 174.7,
+                min_lat: -36.9,
+                max_lon: 174.8,
+                max_lat: -36.8
+            })
+        );
+        assert!("174.7,-36.9,174.8".parse::<BoundingBox>().is_err());
+        assert!("174.8,-36.9,174.7,-36.8".parse::<BoundingBox>().is_err());
+        assert!("a,b,c,d".parse::<BoundingBox>().is_err());
+
+        let subset = Subset {
+            bbox: "1,2,3,4".parse().ok(),
+            agencies: vec![],
+            routes: vec!["R1".into(), "R2".into()],
+        };
+        assert_eq!(subset.to_string(), "bbox=1,2,3,4;route=R1,R2");
+    }
+}
@@ -1,4 +1,21 @@
 use indicatif::{ProgressBar, ProgressStyle};
+use sha2::{Digest, Sha256};
+use tokio_postgres::{Client, NoTls};
+
+use std::path::PathBuf;
+
+use crate::ImporterError;
+
+/// Connects to the database, running the connection in a new task.
+pub async fn connect(db_url: &str) -> Result<Client, ImporterError> {
+    let (client, connection) = tokio_postgres::connect(db_url, NoTls).await?;
+    tokio::spawn(async move {
+        if let Err(e) = connection.await {
+            eprintln!("Database connection error: {}", e);
+        }
+    });
+    Ok(client)
+}
 
 pub fn progress_bar(len: u64, template: &str) -> ProgressBar {
     let bar = ProgressBar::new(len);
@@ -10,3 +27,26 @@ pub fn progress_bar(len: u64, template: &str) -> ProgressBar {
     bar.enable_steady_tick(200);
     bar
 }
+
+/// A spinner for progress of unknown length.
+pub fn spinner(template: &str) -> ProgressBar {
+    let bar = ProgressBar::new_spinner();
+    bar.set_style(ProgressStyle::default_spinner().template(template));
+    bar.enable_steady_tick(200);
+    bar
+}
+
+/// Hex encoded SHA-256 hash of the names and contents of files.
+pub fn hash_files(paths: &[PathBuf]) -> Result<String, std::io::Error> {
+    let mut hasher = Sha256::new();
+    for path in paths {
+        let content = std::fs::read(path)?;
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        // lengths are included so that different files can't give the same input
+        hasher.input((name.len() as u64).to_le_bytes());
+        hasher.input(name.as_bytes());
+        hasher.input((content.len() as u64).to_le_bytes());
+        hasher.input(&content);
+    }
+    Ok(format!("{:x}", hasher.result()))
+}
@@ -0,0 +1,894 @@
+use chrono::NaiveDate;
+use derive_more::Display;
+use serde::Serialize;
+
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+use crate::stop_times::parse_time;
+use crate::ImporterError;
+
+#[derive(Debug, Display, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    #[display(fmt = "error")]
+    Error,
+    #[display(fmt = "warning")]
+    Warning,
+    #[display(fmt = "info")]
+    Info,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Issue {
+    pub severity: Severity,
+    pub file: String,
+    /// Line in the file, if the issue is about a particular row.
+    pub line: Option<u64>,
+    pub message: String,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct Report {
+    pub errors: usize,
+    pub warnings: usize,
+    pub issues: Vec<Issue>,
+}
+
+impl Report {
+    fn push(&mut self, severity: Severity, file: &str, line: Option<u64>, message: String) {
+        match severity {
+            Severity::Error => self.errors += 1,
+            Severity::Warning => self.warnings += 1,
+            Severity::Info => (),
+        }
+        self.issues.push(Issue {
+            severity,
+            file: file.to_string(),
+            line,
+            message,
+        });
+    }
+
+    pub fn print_text(&self) {
+        for issue in &self.issues {
+            match issue.line {
+                Some(line) => println!(
+                    "{}: {}:{}: {}",
+                    issue.severity, issue.file, line, issue.message
+                ),
+                None => println!("{}: {}: {}", issue.severity, issue.file, issue.message),
+            }
+        }
+        println!("{} errors, {} warnings", self.errors, self.warnings);
+    }
+}
+
+/// A csv file loaded into memory, with the line number of each record.
+struct Table {
+    file: &'static str,
+    header: Vec<String>,
+    records: Vec<(u64, csv::StringRecord)>,
+}
+
+impl Table {
+    /// Reads a file, reporting rows with the wrong number of fields and
+    /// leaving them out.
+    fn read(
+        report: &mut Report,
+        path: &Path,
+        file: &'static str,
+    ) -> Result<Option<Table>, ImporterError> {
+        let file_path = path.join(file);
+        if !file_path.exists() {
+            return Ok(None);
+        }
+        let mut reader = csv::ReaderBuilder::new()
+            .flexible(true)
+            .from_path(&file_path)?;
+        let header = reader
+            .headers()?
+            .iter()
+            .map(|h| h.trim().to_string())
+            .collect::<Vec<_>>();
+        let mut records = Vec::new();
+        for record in reader.records() {
+            let record = record?;
+            let line = record.position().map_or(0, |p| p.line());
+            if record.len() != header.len() {
+                report.push(
+                    Severity::Error,
+                    file,
+                    Some(line),
+                    format!(
+                        "row has {} fields, the header has {}",
+                        record.len(),
+                        header.len()
+                    ),
+                );
+                continue;
+            }
+            records.push((line, record));
+        }
+        Ok(Some(Table {
+            file,
+            header,
+            records,
+        }))
+    }
+
+    fn column(&self, name: &str) -> Option<usize> {
+        self.header.iter().position(|h| h == name)
+    }
+
+    /// (line, value) of each row, empty if the column does not exist.
+    fn values<'a>(&'a self, name: &str) -> impl Iterator<Item = (u64, &'a str)> + 'a {
+        let column = self.column(name);
+        self.records
+            .iter()
+            .filter_map(move |(line, r)| column.map(|c| (*line, r.get(c).unwrap_or("").trim())))
+    }
+
+    fn keys(&self, name: &str) -> HashSet<&str> {
+        self.values(name).map(|(_, v)| v).collect()
+    }
+}
+
+/// Files and the columns which the importer needs in them.
+static REQUIRED_COLUMNS: [(&str, &[&str]); 8] = [
+    (
+        "agency.txt",
+        &["agency_id", "agency_name", "agency_url", "agency_timezone"],
+    ),
+    (
+        "stops.txt",
+        &["stop_id", "stop_name", "stop_lat", "stop_lon"],
+    ),
+    ("routes.txt", &["route_id", "agency_id", "route_type"]),
+    ("trips.txt", &["route_id", "service_id", "trip_id"]),
+    (
+        "stop_times.txt",
+        &[
+            "trip_id",
+            "arrival_time",
+            "departure_time",
+            "stop_id",
+            "stop_sequence",
+        ],
+    ),
+    (
+        "calendar.txt",
+        &[
+            "service_id",
+            "monday",
+            "tuesday",
+            "wednesday",
+            "thursday",
+            "friday",
+            "saturday",
+            "sunday",
+            "start_date",
+            "end_date",
+        ],
+    ),
+    (
+        "calendar_dates.txt",
+        &["service_id", "date", "exception_type"],
+    ),
+    (
+        "shapes.txt",
+        &[
+            "shape_id",
+            "shape_pt_lat",
+            "shape_pt_lon",
+            "shape_pt_sequence",
+        ],
+    ),
+];
+
+/// Checks a GTFS feed in a directory before it is imported.
+pub fn validate(path: &Path, today: NaiveDate) -> Result<Report, ImporterError> {
+    let mut report = Report::default();
+    let mut tables = HashMap::new();
+
+    for (file, columns) in REQUIRED_COLUMNS.iter() {
+        match Table::read(&mut report, path, file)? {
+            Some(table) => {
+                for column in columns.iter() {
+                    if table.column(column).is_none() {
+                        report.push(
+                            Severity::Error,
+                            file,
+                            None,
+                            format!("missing required column {}", column),
+                        );
+                    }
+                }
+                if table.records.is_empty() {
+                    report.push(Severity::Warning, file, None, "file has no rows".into());
+                }
+                tables.insert(*file, table);
+            }
+            None => report.push(Severity::Error, file, None, "missing file".into()),
+        }
+    }
+    let table = |file| tables.get(file);
+
+    if let Some(t) = table("agency.txt") {
+        check_unique(&mut report, t, &["agency_id"]);
+    }
+    if let Some(t) = table("stops.txt") {
+        check_unique(&mut report, t, &["stop_id"]);
+        let stop_ids = t.keys("stop_id");
+        check_references(&mut report, t, "parent_station", &stop_ids, "stops.txt");
+    }
+    if let Some(t) = table("routes.txt") {
+        check_unique(&mut report, t, &["route_id"]);
+        if let Some(agency) = table("agency.txt") {
+            check_references(
+                &mut report,
+                t,
+                "agency_id",
+                &agency.keys("agency_id"),
+                "agency.txt",
+            );
+        }
+    }
+    if let Some(t) = table("calendar.txt") {
+        check_unique(&mut report, t, &["service_id"]);
+    }
+    if let Some(t) = table("calendar_dates.txt") {
+        check_unique(&mut report, t, &["service_id", "date"]);
+    }
+    if let Some(t) = table("shapes.txt") {
+        check_unique(&mut report, t, &["shape_id", "shape_pt_sequence"]);
+    }
+    if let Some(t) = table("trips.txt") {
+        check_unique(&mut report, t, &["trip_id"]);
+        if let Some(routes) = table("routes.txt") {
+            check_references(
+                &mut report,
+                t,
+                "route_id",
+                &routes.keys("route_id"),
+                "routes.txt",
+            );
+        }
+        let mut service_ids = HashSet::new();
+        for file in &["calendar.txt", "calendar_dates.txt"] {
+            if let Some(c) = table(file) {
+                service_ids.extend(c.keys("service_id"));
+            }
+        }
+        check_references(
+            &mut report,
+            t,
+            "service_id",
+            &service_ids,
+            "calendar.txt or calendar_dates.txt",
+        );
+        if let Some(shapes) = table("shapes.txt") {
+            check_references(
+                &mut report,
+                t,
+                "shape_id",
+                &shapes.keys("shape_id"),
+                "shapes.txt",
+            );
+        }
+    }
+    if let Some(t) = table("stop_times.txt") {
+        check_unique(&mut report, t, &["trip_id", "stop_sequence"]);
+        if let Some(trips) = table("trips.txt") {
+            check_references(
+                &mut report,
+                t,
+                "trip_id",
+                &trips.keys("trip_id"),
+                "trips.txt",
+            );
+        }
+        if let Some(stops) = table("stops.txt") {
+            check_references(
+                &mut report,
+                t,
+                "stop_id",
+                &stops.keys("stop_id"),
+                "stops.txt",
+            );
+        }
+        check_stop_times(&mut report, t);
+    }
+    check_calendar(
+        &mut report,
+        table("calendar.txt"),
+        table("calendar_dates.txt"),
+        today,
+    );
+
+    report.issues.sort_by_key(|i| i.severity);
+    Ok(report)
+}
+
+fn check_unique(report: &mut Report, table: &Table, columns: &[&str]) {
+    let indices = match columns
+        .iter()
+        .map(|c| table.column(c))
+        .collect::<Option<Vec<_>>>()
+    {
+        Some(i) => i,
+        None => return, // missing columns are already reported
+    };
+    let mut seen = HashMap::new();
+    for (line, record) in &table.records {
+        let key = indices
+            .iter()
+            .map(|&i| record.get(i).unwrap_or("").trim())
+            .collect::<Vec<_>>();
+        if let Some(first) = seen.insert(key.clone(), *line) {
+            report.push(
+                Severity::Error,
+                table.file,
+                Some(*line),
+                format!(
+                    "duplicate {} \"{}\", first defined on line {}",
+                    columns.join(", "),
+                    key.join(", "),
+                    first
+                ),
+            );
+        }
+    }
+}
+
+fn check_references(
+    report: &mut Report,
+    table: &Table,
+    column: &str,
+    keys: &HashSet<&str>,
+    referenced_file: &str,
+) {
+    for (line, value) in table.values(column) {
+        if !value.is_empty() && !keys.contains(value) {
+            report.push(
+                Severity::Error,
+                table.file,
+                Some(line),
+                format!("{} \"{}\" not found in {}", column, value, referenced_file),
+            );
+        }
+    }
+}
+
+/// Checks that times can be parsed, and do not decrease along each trip.
+fn check_stop_times(report: &mut Report, table: &Table) {
+    let columns = ["trip_id", "stop_sequence", "arrival_time", "departure_time"];
+    let (trip_id, stop_sequence, arrival_time, departure_time) = match columns
+        .iter()
+        .map(|c| table.column(c))
+        .collect::<Option<Vec<_>>>()
+    {
+        Some(c) => (c[0], c[1], c[2], c[3]),
+        None => return,
+    };
+
+    // (stop_sequence, line, arrival, departure) of each trip
+    let mut trips: HashMap<&str, Vec<_>> = HashMap::new();
+    for (line, record) in &table.records {
+        let field = |i| record.get(i).unwrap_or("").trim();
+        let sequence = match field(stop_sequence).parse::<u32>() {
+            Ok(s) => s,
+            Err(_) => {
+                report.push(
+                    Severity::Error,
+                    table.file,
+                    Some(*line),
+                    format!("invalid stop_sequence \"{}\"", field(stop_sequence)),
+                );
+                continue;
+            }
+        };
+        let mut time = |i| {
+            parse_time(field(i)).unwrap_or_else(|e| {
+                report.push(Severity::Error, table.file, Some(*line), e);
+                None
+            })
+        };
+        let arrival = time(arrival_time);
+        let departure = time(departure_time);
+        if let (Some(a), Some(d)) = (arrival, departure) {
+            if d < a {
+                report.push(
+                    Severity::Error,
+                    table.file,
+                    Some(*line),
+                    "departure_time is before arrival_time".into(),
+                );
+            }
+        }
+        trips
+            .entry(field(trip_id))
+            .or_default()
+            .push((sequence, *line, arrival, departure));
+    }
+
+    let mut trips = trips.into_iter().collect::<Vec<_>>();
+    trips.sort_unstable_by_key(|(_, rows)| rows.iter().map(|r| r.1).min());
+    for (trip_id, mut rows) in trips {
+        rows.sort_unstable();
+        let first = rows.first().unwrap();
+        let last = rows.last().unwrap();
+        if first.2.is_none() && first.3.is_none() {
+            report.push(
+                Severity::Error,
+                table.file,
+                Some(first.1),
+                format!("first stop of trip \"{}\" has no time", trip_id),
+            );
+        }
+        if rows.len() > 1 && last.2.is_none() && last.3.is_none() {
+            report.push(
+                Severity::Error,
+                table.file,
+                Some(last.1),
+                format!("last stop of trip \"{}\" has no time", trip_id),
+            );
+        }
+        if rows.len() == 1 {
+            report.push(
+                Severity::Warning,
+                table.file,
+                Some(first.1),
+                format!("trip \"{}\" only has one stop", trip_id),
+            );
+        }
+
+        let mut previous: Option<i32> = None;
+        for &(_, line, arrival, departure) in &rows {
+            if let (Some(p), Some(t)) = (previous, arrival.or(departure)) {
+                if t < p {
+                    report.push(
+                        Severity::Error,
+                        table.file,
+                        Some(line),
+                        format!("time goes backwards along trip \"{}\"", trip_id),
+                    );
+                }
+            }
+            previous = departure.or(arrival).or(previous);
+        }
+    }
+}
+
+/// The trimmed value in a column of a record, or "" if there is no such column.
+fn field(record: &csv::StringRecord, column: Option<usize>) -> &str {
+    column.and_then(|i| record.get(i)).unwrap_or("").trim()
+}
+
+fn parse_date(s: &str) -> Option<NaiveDate> {
+    NaiveDate::parse_from_str(s, "%Y%m%d").ok()
+}
+
+/// Checks dates, and that the feed has service around `today`.
+fn check_calendar(
+    report: &mut Report,
+    calendar: Option<&Table>,
+    calendar_dates: Option<&Table>,
+    today: NaiveDate,
+) {
+    let mut first_day: Option<NaiveDate> = None;
+    let mut last_day: Option<NaiveDate> = None;
+    let mut extend = |d: NaiveDate| {
+        first_day = Some(first_day.map_or(d, |f| f.min(d)));
+        last_day = Some(last_day.map_or(d, |l| l.max(d)));
+    };
+
+    if let Some(t) = calendar {
+        let days = [
+            "monday",
+            "tuesday",
+            "wednesday",
+            "thursday",
+            "friday",
+            "saturday",
+            "sunday",
+        ]
+        .iter()
+        .filter_map(|d| t.column(d))
+        .collect::<Vec<_>>();
+        let start = t.column("start_date");
+        let end = t.column("end_date");
+
+        for (line, record) in &t.records {
+            let dates = (
+                parse_date(field(record, start)),
+                parse_date(field(record, end)),
+            );
+            let (start_date, end_date) = match dates {
+                (Some(s), Some(e)) => (s, e),
+                _ => {
+                    report.push(
+                        Severity::Error,
+                        t.file,
+                        Some(*line),
+                        "invalid start_date or end_date, expected YYYYMMDD".into(),
+                    );
+                    continue;
+                }
+            };
+            if end_date < start_date {
+                report.push(
+                    Severity::Error,
+                    t.file,
+                    Some(*line),
+                    "end_date is before start_date".into(),
+                );
+            }
+            if days.iter().all(|&d| field(record, Some(d)) == "0") {
+                report.push(
+                    Severity::Warning,
+                    t.file,
+                    Some(*line),
+                    "service does not run on any day of the week".into(),
+                );
+            }
+            extend(start_date);
+            extend(end_date);
+        }
+    }
+    if let Some(t) = calendar_dates {
+        let date = t.column("date");
+        let exception_type = t.column("exception_type");
+        for (line, record) in &t.records {
+            match parse_date(field(record, date)) {
+                // only added dates extend the range of service
+                Some(d) if field(record, exception_type) == "1" => extend(d),
+                Some(_) => (),
+                None => report.push(
+                    Severity::Error,
+                    t.file,
+                    Some(*line),
+                    format!(
+                        "invalid date \"{}\", expected YYYYMMDD",
+                        field(record, date)
+                    ),
+                ),
+            }
+        }
+    }
+
+    let file = "calendar.txt";
+    match (first_day, last_day) {
+        (Some(first), Some(last)) => {
+            report.push(
+                Severity::Info,
+                file,
+                None,
+                format!("service runs from {} to {}", first, last),
+            );
+            if last < today {
+                report.push(
+                    Severity::Warning,
+                    file,
+                    None,
+                    format!("feed expired on {}", last),
+                );
+            } else if first > today {
+                report.push(
+                    Severity::Warning,
+                    file,
+                    None,
+                    format!("feed only starts on {}", first),
+                );
+            }
+        }
+        _ => report.push(
+            Severity::Warning,
+            file,
+            None,
+            "no dates with service found".into(),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn feed(files: &[(&str, &str)]) -> tempfile::TempDir {
+        let dir = tempfile::tempdir().unwrap();
+        for (name, content) in files {
+            std::fs::write(dir.path().join(name), content).unwrap();
+        }
+        dir
+    }
+
+    /// A feed with no errors or warnings in 2020, with `files` replaced.
+    fn valid_feed(files: &[(&str, &str)]) -> tempfile::TempDir {
+        let mut all = vec![
+            (
+                "agency.txt",
+                "agency_id,agency_name,agency_url,agency_timezone\n\
+                 A,Agency,https://a,Pacific/Auckland\n",
+            ),
+            (
+                "stops.txt",
+                "stop_id,stop_name,stop_lat,stop_lon\nS1,One,0,0\nS2,Two,0,0\n",
+            ),
+            ("routes.txt", "route_id,agency_id,route_type\nR,A,3\n"),
+            ("trips.txt", "route_id,service_id,trip_id\nR,C,T\n"),
+            (
+                "stop_times.txt",
+                "trip_id,arrival_time,departure_time,stop_id,stop_sequence\n\
+                 T,08:00:00,08:00:00,S1,1\n\
+                 T,08:10:00,08:10:00,S2,2\n",
+            ),
+            (
+                "calendar.txt",
+                "service_id,monday,tuesday,wednesday,thursday,friday,saturday,sunday,\
+                 start_date,end_date\n\
+                 C,1,1,1,1,1,0,0,20200101,20201231\n",
+            ),
+            (
+                "calendar_dates.txt",
+                "service_id,date,exception_type\nC,20200106,2\n",
+            ),
+            (
+                "shapes.txt",
+                "shape_id,shape_pt_lat,shape_pt_lon,shape_pt_sequence\nSH,0,0,1\n",
+            ),
+        ];
+        for file in files {
+            all.retain(|(name, _)| *name != file.0);
+            all.push(*file);
+        }
+        feed(&all)
+    }
+
+    /// (severity, file, line, message) of the errors and warnings.
+    fn issues(
+        dir: &tempfile::TempDir,
+        today: NaiveDate,
+    ) -> Vec<(Severity, String, Option<u64>, String)> {
+        validate(dir.path(), today)
+            .unwrap()
+            .issues
+            .into_iter()
+            .filter(|i| i.severity != Severity::Info)
+            .map(|i| (i.severity, i.file, i.line, i.message))
+            .collect()
+    }
+
+    fn date(y: i32, m: u32, d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(y, m, d).unwrap()
+    }
+
+    fn error(
+        file: &str,
+        line: Option<u64>,
+        message: &str,
+    ) -> (Severity, String, Option<u64>, String) {
+        (Severity::Error, file.into(), line, message.into())
+    }
+
+    #[test]
+    fn valid() {
+        assert_eq!(issues(&valid_feed(&[]), date(2020, 6, 1)), vec![]);
+    }
+
+    #[test]
+    fn missing_files_and_columns() {
+        let dir = valid_feed(&[(
+            "agency.txt",
+            "agency_id,agency_name,agency_url\nA,Agency,https://a\n",
+        )]);
+        std::fs::remove_file(dir.path().join("shapes.txt")).unwrap();
+        assert_eq!(
+            issues(&dir, date(2020, 6, 1)),
+            vec![
+                error(
+                    "agency.txt",
+                    None,
+                    "missing required column agency_timezone"
+                ),
+                error("shapes.txt", None, "missing file"),
+            ]
+        );
+    }
+
+    #[test]
+    fn duplicates() {
+        let dir = valid_feed(&[
+            (
+                "stops.txt",
+                "stop_id,stop_name,stop_lat,stop_lon\nS1,One,0,0\nS2,Two,0,0\nS1,Three,0,0\n",
+            ),
+            (
+                "calendar_dates.txt",
+                "service_id,date,exception_type\nC,20200106,2\nC,20200107,2\nC,20200106,1\n",
+            ),
+        ]);
+        assert_eq!(
+            issues(&dir, date(2020, 6, 1)),
+            vec![
+                error(
+                    "stops.txt",
+                    Some(4),
+                    "duplicate stop_id \"S1\", first defined on line 2"
+                ),
+                error(
+                    "calendar_dates.txt",
+                    Some(4),
+                    "duplicate service_id, date \"C, 20200106\", first defined on line 2"
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn references() {
+        let dir = valid_feed(&[
+            (
+                "routes.txt",
+                "route_id,agency_id,route_type\nR,A,3\nR2,B,3\n",
+            ),
+            ("trips.txt", "route_id,service_id,trip_id\nR,C,T\nR3,C,T2\n"),
+            (
+                "stop_times.txt",
+                "trip_id,arrival_time,departure_time,stop_id,stop_sequence\n\
+                 T,08:00:00,08:00:00,S1,1\n\
+                 T,08:10:00,08:10:00,S3,2\n\
+                 T2,08:00:00,08:00:00,S1,1\n\
+                 T2,08:10:00,08:10:00,S2,2\n\
+                 T4,08:00:00,08:00:00,S1,1\n\
+                 T4,08:10:00,08:10:00,S2,2\n",
+            ),
+        ]);
+        assert_eq!(
+            issues(&dir, date(2020, 6, 1)),
+            vec![
+                error(
+                    "routes.txt",
+                    Some(3),
+                    "agency_id \"B\" not found in agency.txt"
+                ),
+                error(
+                    "trips.txt",
+                    Some(3),
+                    "route_id \"R3\" not found in routes.txt"
+                ),
+                error(
+                    "stop_times.txt",
+                    Some(6),
+                    "trip_id \"T4\" not found in trips.txt"
+                ),
+                error(
+                    "stop_times.txt",
+                    Some(7),
+                    "trip_id \"T4\" not found in trips.txt"
+                ),
+                error(
+                    "stop_times.txt",
+                    Some(3),
+                    "stop_id \"S3\" not found in stops.txt"
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn calendar_coverage() {
+        let dir = valid_feed(&[(
+            "calendar_dates.txt",
+            "service_id,date,exception_type\nC,20210110,1\n",
+        )]);
+        let warning = |message: &str| {
+            (
+                Severity::Warning,
+                "calendar.txt".into(),
+                None,
+                message.into(),
+            )
+        };
+        // an added date extends the service
+        assert_eq!(
+            issues(&dir, date(2021, 1, 11)),
+            vec![warning("feed expired on 2021-01-10")]
+        );
+        assert_eq!(issues(&dir, date(2021, 1, 10)), vec![]);
+        assert_eq!(
+            issues(&dir, date(2019, 12, 31)),
+            vec![warning("feed only starts on 2020-01-01")]
+        );
+    }
+
+    #[test]
+    fn ragged_rows() {
+        let dir = valid_feed(&[(
+            "stops.txt",
+            "stop_id,stop_name,stop_lat,stop_lon\nS1,One,0,0\nS2,Two,0\nS3,Three,0,0,extra\n",
+        )]);
+        // the rows are left out, so S2 isn't found either
+        assert_eq!(
+            issues(&dir, date(2020, 6, 1)),
+            vec![
+                error("stops.txt", Some(3), "row has 3 fields, the header has 4"),
+                error("stops.txt", Some(4), "row has 5 fields, the header has 4"),
+                error(
+                    "stop_times.txt",
+                    Some(3),
+                    "stop_id \"S2\" not found in stops.txt"
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn json() {
+        let dir = valid_feed(&[("routes.txt", "route_id,agency_id,route_type\nR,B,3\n")]);
+        let report = validate(dir.path(), date(2020, 6, 1)).unwrap();
+        assert_eq!(
+            serde_json::to_value(&report).unwrap(),
+            serde_json::json!({
+                "errors": 1,
+                "warnings": 0,
+                "issues": [
+                    {
+                        "severity": "error",
+                        "file": "routes.txt",
+                        "line": 2,
+                        "message": "agency_id \"B\" not found in agency.txt",
+                    },
+                    {
+                        "severity": "info",
+                        "file": "calendar.txt",
+                        "line": null,
+                        "message": "service runs from 2020-01-01 to 2020-12-31",
+                    },
+                ],
+            })
+        );
+    }
+
+    #[test]
+    fn stop_times() {
+        let dir = feed(&[
+            (
+                "stops.txt",
+                "stop_id,stop_name,stop_lat,stop_lon\nA,A,0,0\n",
+            ),
+            ("trips.txt", "route_id,service_id,trip_id\nR,S,T\nR,S,V\n"),
+            (
+                "stop_times.txt",
+                "trip_id,arrival_time,departure_time,stop_id,stop_sequence\n\
+                 T,08:00:00,08:00:00,A,1\n\
+                 T,07:00:00,07:00:00,B,2\n\
+                 T,08:10:00,08:10:00,A,2\n\
+                 V,7:00,07:00:00,A,1\n\
+                 V,07:10:00,07:10:00,A,2\n\
+                 U,,,A,1\n",
+            ),
+        ]);
+        let report = validate(dir.path(), NaiveDate::from_ymd_opt(2020, 1, 1).unwrap()).unwrap();
+        let issues = report
+            .issues
+            .iter()
+            .filter(|i| i.file == "stop_times.txt" && i.severity == Severity::Error)
+            .map(|i| (i.line, &i.message[..]))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            issues,
+            vec![
+                (
+                    Some(4),
+                    "duplicate trip_id, stop_sequence \"T, 2\", first defined on line 3"
+                ),
+                (Some(7), "trip_id \"U\" not found in trips.txt"),
+                (Some(3), "stop_id \"B\" not found in stops.txt"),
+                (Some(5), "invalid time \"7:00\", expected HH:MM:SS"),
+                (Some(3), "time goes backwards along trip \"T\""),
+                (Some(7), "first stop of trip \"U\" has no time"),
+            ]
+        );
+    }
+}